@@ -0,0 +1,234 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use std::iter::{FromIterator, IntoIterator};
+use std::mem;
+use std::slice;
+use std::vec;
+
+type Entry = ((String, Option<String>), String);
+
+#[derive(Clone, Debug, Default)]
+/// A collection of attributes, keeping the order in which they were inserted
+///
+/// Attributes are keyed by a `(name, namespace)` tuple, like in a `HashMap`.
+/// Iteration yields attributes in insertion order, replacing the value of an
+/// existing attribute keeps its position.
+/// Two collections are equal if they contain the same attributes, regardless of order.
+///
+/// ~~~
+/// use xml::Attributes;
+///
+/// let mut attrs = Attributes::new();
+/// attrs.insert(("b".to_owned(), None), "1".to_owned());
+/// attrs.insert(("a".to_owned(), None), "2".to_owned());
+/// let names: Vec<_> = attrs.keys().map(|&(ref name, _)| &name[..]).collect();
+/// assert_eq!(names, vec!["b", "a"]);
+/// ~~~
+pub struct Attributes {
+    entries: Vec<Entry>
+}
+
+impl Attributes {
+    /// Returns a new, empty collection of attributes
+    pub fn new() -> Attributes {
+        Attributes { entries: Vec::new() }
+    }
+
+    /// Returns a new, empty collection of attributes with space for `capacity` attributes
+    pub fn with_capacity(capacity: usize) -> Attributes {
+        Attributes { entries: Vec::with_capacity(capacity) }
+    }
+
+    /// Returns the number of attributes
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if there are no attributes
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes all attributes
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn position(&self, name: &str, ns: Option<&str>) -> Option<usize> {
+        self.entries.iter().position(|entry| {
+            let (ref ename, ref ens) = entry.0;
+            ename == name && ens.as_ref().map(|x| &x[..]) == ns
+        })
+    }
+
+    /// Returns the value of the attribute with the given `(name, namespace)` key
+    pub fn get(&self, key: &(String, Option<String>)) -> Option<&String> {
+        self.get_by_name(&key.0, key.1.as_ref().map(|x| &x[..]))
+    }
+
+    /// Returns the value of the attribute with the given name and namespace
+    pub fn get_by_name(&self, name: &str, ns: Option<&str>) -> Option<&String> {
+        self.position(name, ns).map(|idx| &self.entries[idx].1)
+    }
+
+    /// Returns a mutable reference to the value of the attribute with the given key
+    pub fn get_mut(&mut self, key: &(String, Option<String>)) -> Option<&mut String> {
+        match self.position(&key.0, key.1.as_ref().map(|x| &x[..])) {
+            Some(idx) => Some(&mut self.entries[idx].1),
+            None => None
+        }
+    }
+
+    /// Returns true if an attribute with the given key exists
+    pub fn contains_key(&self, key: &(String, Option<String>)) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Sets the value of an attribute, returning the previous value.
+    /// New attributes are appended, existing ones keep their position.
+    pub fn insert(&mut self, key: (String, Option<String>), value: String) -> Option<String> {
+        match self.position(&key.0, key.1.as_ref().map(|x| &x[..])) {
+            Some(idx) => Some(mem::replace(&mut self.entries[idx].1, value)),
+            None => {
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// Removes an attribute, returning its value
+    pub fn remove(&mut self, key: &(String, Option<String>)) -> Option<String> {
+        self.remove_by_name(&key.0, key.1.as_ref().map(|x| &x[..]))
+    }
+
+    /// Removes the attribute with the given name and namespace, returning its value
+    pub fn remove_by_name(&mut self, name: &str, ns: Option<&str>) -> Option<String> {
+        self.position(name, ns).map(|idx| self.entries.remove(idx).1)
+    }
+
+    /// Returns an iterator over the attributes, in insertion order
+    pub fn iter(&self) -> AttributeIter<'_> {
+        AttributeIter { inner: self.entries.iter() }
+    }
+
+    /// Returns an iterator over the `(name, namespace)` keys, in insertion order
+    pub fn keys(&self) -> impl Iterator<Item=&(String, Option<String>)> {
+        self.entries.iter().map(|entry| &entry.0)
+    }
+
+    /// Returns an iterator over the values, in insertion order
+    pub fn values(&self) -> impl Iterator<Item=&String> {
+        self.entries.iter().map(|entry| &entry.1)
+    }
+}
+
+impl PartialEq for Attributes {
+    fn eq(&self, other: &Attributes) -> bool {
+        self.len() == other.len() && self.iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl Eq for Attributes {}
+
+/// An iterator over the attributes of an `Attributes` collection
+pub struct AttributeIter<'a> {
+    inner: slice::Iter<'a, Entry>
+}
+
+impl<'a> Iterator for AttributeIter<'a> {
+    type Item = (&'a (String, Option<String>), &'a String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|entry| (&entry.0, &entry.1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> IntoIterator for &'a Attributes {
+    type Item = (&'a (String, Option<String>), &'a String);
+    type IntoIter = AttributeIter<'a>;
+
+    fn into_iter(self) -> AttributeIter<'a> {
+        self.iter()
+    }
+}
+
+impl IntoIterator for Attributes {
+    type Item = ((String, Option<String>), String);
+    type IntoIter = vec::IntoIter<Entry>;
+
+    fn into_iter(self) -> vec::IntoIter<Entry> {
+        self.entries.into_iter()
+    }
+}
+
+impl FromIterator<((String, Option<String>), String)> for Attributes {
+    fn from_iter<I>(iter: I) -> Attributes
+        where I: IntoIterator<Item=((String, Option<String>), String)>
+    {
+        let mut attributes = Attributes::new();
+        attributes.extend(iter);
+        attributes
+    }
+}
+
+impl Extend<((String, Option<String>), String)> for Attributes {
+    fn extend<I>(&mut self, iter: I)
+        where I: IntoIterator<Item=((String, Option<String>), String)>
+    {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Attributes;
+
+    fn key(name: &str) -> (String, Option<String>) {
+        (name.to_owned(), None)
+    }
+
+    #[test]
+    fn test_insertion_order() {
+        let mut attrs = Attributes::new();
+        attrs.insert(key("z"), "1".to_owned());
+        attrs.insert(key("a"), "2".to_owned());
+        attrs.insert(key("m"), "3".to_owned());
+        assert_eq!(attrs.insert(key("a"), "4".to_owned()), Some("2".to_owned()));
+        assert_eq!(attrs.remove(&key("z")), Some("1".to_owned()));
+        attrs.insert(key("z"), "5".to_owned());
+
+        let v: Vec<_> = attrs.iter().map(|(k, v)| (&k.0[..], &v[..])).collect();
+        assert_eq!(v, vec![("a", "4"), ("m", "3"), ("z", "5")]);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let mut attrs = Attributes::new();
+        attrs.insert(key("a"), "1".to_owned());
+        attrs.insert(("a".to_owned(), Some("urn:x".to_owned())), "2".to_owned());
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs.get_by_name("a", None).map(|x| &x[..]), Some("1"));
+        assert_eq!(attrs.get_by_name("a", Some("urn:x")).map(|x| &x[..]), Some("2"));
+        assert_eq!(attrs.get_by_name("a", Some("urn:y")), None);
+    }
+
+    #[test]
+    fn test_eq_ignores_order() {
+        let a: Attributes = vec![(key("a"), "1".to_owned()), (key("b"), "2".to_owned())]
+                                .into_iter().collect();
+        let b: Attributes = vec![(key("b"), "2".to_owned()), (key("a"), "1".to_owned())]
+                                .into_iter().collect();
+        assert_eq!(a, b);
+        assert!(a != Attributes::new());
+    }
+}
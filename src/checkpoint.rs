@@ -0,0 +1,274 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use parser::{Parser, ParserError};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+/// A snapshot of a `Parser`'s state at an event boundary, as returned by `Parser::checkpoint()`
+///
+/// A checkpoint can be converted to a string and parsed back, to be stored until
+/// parsing is resumed using `Parser::from_checkpoint()`.
+///
+/// ~~~
+/// use xml::{Checkpoint, Parser};
+///
+/// let input = "<feed><entry>1</entry><entry>2</entry></feed>";
+/// let mut p = Parser::new();
+/// p.feed_str(input);
+/// let _ = (&mut p).take(4).count();
+/// let saved = p.checkpoint().unwrap().to_string();
+///
+/// let checkpoint: Checkpoint = saved.parse().unwrap();
+/// let mut p = Parser::from_checkpoint(&checkpoint).unwrap();
+/// p.feed_str(&input[checkpoint.offset() as usize..]);
+/// assert_eq!(p.count(), 4);
+/// ~~~
+pub struct Checkpoint {
+    offset: u64,
+    state: String
+}
+
+impl Checkpoint {
+    pub(crate) fn new(offset: u64, state: String) -> Checkpoint {
+        Checkpoint { offset, state }
+    }
+
+    /// Returns the number of bytes of input the parser had consumed,
+    /// parsing is to be resumed with the input following them
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub(crate) fn state(&self) -> &str {
+        &self.state
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rustyxml-checkpoint {} {}", self.offset, self.state)
+    }
+}
+
+impl FromStr for Checkpoint {
+    type Err = ParserError;
+
+    #[inline]
+    fn from_str(data: &str) -> Result<Checkpoint, ParserError> {
+        let err = ParserError { line: 0, col: 0, msg: "Invalid checkpoint" };
+        let rest = data.strip_prefix("rustyxml-checkpoint ").ok_or_else(|| err.clone())?;
+        let (offset, state) = rest.split_at(rest.find(' ').ok_or_else(|| err.clone())?);
+        let offset = offset.parse().map_err(|_| err)?;
+        let checkpoint = Checkpoint::new(offset, state[1..].to_owned());
+        Parser::from_checkpoint(&checkpoint)?;
+        Ok(checkpoint)
+    }
+}
+
+// Encodes parser state as a sequence of space separated tokens.
+// Strings are prefixed with their length in bytes, so they need no escaping.
+pub(crate) struct StateWriter {
+    out: String
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> StateWriter {
+        StateWriter { out: String::new() }
+    }
+
+    fn token(&mut self, token: &str) {
+        if !self.out.is_empty() {
+            self.out.push(' ');
+        }
+        self.out.push_str(token);
+    }
+
+    pub(crate) fn num(&mut self, num: u64) {
+        self.token(&num.to_string());
+    }
+
+    pub(crate) fn flag(&mut self, flag: bool) {
+        self.token(if flag { "1" } else { "0" });
+    }
+
+    pub(crate) fn string(&mut self, s: &str) {
+        self.token(&format!("{}:{}", s.len(), s));
+    }
+
+    pub(crate) fn opt_string(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => self.string(s),
+            None => self.token("-")
+        }
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.out
+    }
+}
+
+// Decodes parser state written by a StateWriter.
+// All methods return None if the data does not match.
+pub(crate) struct StateReader<'a> {
+    rest: &'a str
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a str) -> StateReader<'a> {
+        StateReader { rest: data }
+    }
+
+    fn token(&mut self) -> Option<&'a str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let end = self.rest.find(' ').unwrap_or(self.rest.len());
+        let token = &self.rest[..end];
+        self.rest = self.rest[end..].strip_prefix(' ').unwrap_or("");
+        Some(token)
+    }
+
+    pub(crate) fn num(&mut self) -> Option<u64> {
+        self.token()?.parse().ok()
+    }
+
+    pub(crate) fn flag(&mut self) -> Option<bool> {
+        match self.token()? {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => None
+        }
+    }
+
+    pub(crate) fn string(&mut self) -> Option<String> {
+        self.opt_string()?
+    }
+
+    pub(crate) fn opt_string(&mut self) -> Option<Option<String>> {
+        if let Some(rest) = self.rest.strip_prefix('-') {
+            self.rest = rest.strip_prefix(' ').unwrap_or(rest);
+            return Some(None);
+        }
+        let colon = self.rest.find(':')?;
+        let len: usize = self.rest[..colon].parse().ok()?;
+        let s = self.rest.get(colon + 1..colon + 1 + len)?;
+        let rest = &self.rest[colon + 1 + len..];
+        self.rest = match rest.strip_prefix(' ') {
+            Some(rest) => rest,
+            None if rest.is_empty() => rest,
+            None => return None
+        };
+        Some(Some(s.to_owned()))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, StateReader, StateWriter};
+    use parser::{Event, Parser};
+
+    #[test]
+    fn test_state_encoding() {
+        let mut w = StateWriter::new();
+        w.num(42);
+        w.string("a b:c");
+        w.opt_string(None);
+        w.flag(true);
+        w.string("");
+        let data = w.finish();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.num(), Some(42));
+        assert_eq!(r.string(), Some("a b:c".to_owned()));
+        assert_eq!(r.opt_string(), Some(None));
+        assert_eq!(r.flag(), Some(true));
+        assert_eq!(r.string(), Some(String::new()));
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn test_resume() {
+        let input = "<?xml version='1.1'?><a xmlns='urn:a' xmlns:b='urn:b'>\u{e9}\r\n<b:c x='1'/>\
+                     <![CDATA[d]]></a>";
+        let mut p = Parser::new();
+        p.set_coalesce_text(true);
+        p.feed_str(input);
+        let expected: Vec<_> = p.collect();
+
+        let mut resumed = 0;
+        for n in 0..expected.len() {
+            let mut p = Parser::new();
+            p.set_coalesce_text(true);
+            p.feed_str(input);
+            let _ = (&mut p).take(n).count();
+            let checkpoint = match p.checkpoint() {
+                Some(checkpoint) => checkpoint,
+                None => continue
+            };
+            let checkpoint: Checkpoint = checkpoint.to_string().parse().unwrap();
+            let mut p = Parser::from_checkpoint(&checkpoint).unwrap();
+            p.feed_bytes(&input.as_bytes()[checkpoint.offset() as usize..]).unwrap();
+            let events: Vec<_> = p.collect();
+            assert_eq!(&events[..], &expected[n..]);
+            resumed += 1;
+        }
+        // Coalesced text is returned with the event following it already queued
+        assert_eq!(resumed, expected.len() - 2);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut p = Parser::new();
+        p.feed_str("<stream><a/>");
+        let _ = p.count();
+        p.reset(false);
+
+        let input = "<stream><b/><c/></stream>";
+        p.feed_str(input);
+        let _ = (&mut p).take(3).count();
+        let checkpoint = p.checkpoint().unwrap();
+        assert_eq!(checkpoint.offset(), 12);
+
+        let mut p = Parser::from_checkpoint(&checkpoint).unwrap();
+        p.feed_str(&input[checkpoint.offset() as usize..]);
+        assert_eq!(p.count(), 3);
+    }
+
+    #[test]
+    fn test_pending_events() {
+        let mut p = Parser::new();
+        p.set_report_prefix_mappings(true);
+        p.feed_str("<a xmlns='urn:a'/>");
+        assert_eq!((&mut p).next(), Some(Ok(Event::StartPrefixMapping {
+            prefix: None,
+            uri: "urn:a".to_owned()
+        })));
+        assert!(p.checkpoint().is_none());
+    }
+
+    #[test]
+    fn test_undecodable() {
+        let mut p = Parser::new();
+        p.set_stop_at_root_end(true);
+        p.feed_bytes(b"<a/>\xff").unwrap();
+        assert_eq!((&mut p).count(), 2);
+        assert!(p.checkpoint().is_none());
+        p.take_remaining_bytes();
+        assert!(p.checkpoint().is_some());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("rustyxml-checkpoint 12 1 0".parse::<Checkpoint>().is_err());
+        assert!("checkpoint".parse::<Checkpoint>().is_err());
+    }
+}
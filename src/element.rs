@@ -4,11 +4,13 @@
 // This project is MIT licensed.
 // Please see the COPYING file for more information.
 
-use {escape, Xml};
+use {escape, Attributes, EndTag, Event, StartTag, Xml, XmlVersion};
 use element_builder::{BuilderError, ElementBuilder};
 use parser::Parser;
+use writer::{write_escaped_as, CountingWriter};
 
 use std::fmt;
+use std::io::{self, Write};
 use std::slice;
 use std::collections::HashMap;
 use std::iter::IntoIterator;
@@ -22,7 +24,7 @@ pub struct Element {
     /// The element's namespace
     pub ns: Option<String>,
     /// The element's attributes
-    pub attributes: HashMap<(String, Option<String>), String>,
+    pub attributes: Attributes,
     /// The element's child `Xml` nodes
     pub children: Vec<Xml>,
     #[doc(hidden)]
@@ -90,6 +92,96 @@ fn fmt_elem(elem: &Element, parent: Option<&Element>, all_prefixes: &HashMap<Str
     }
 }
 
+// The prefix bound to a namespace in the innermost scope declaring it
+pub(crate) fn bound_prefix<'a>(scopes: &[&'a HashMap<String, String>],
+                               ns: Option<&str>) -> io::Result<&'a str> {
+    let ns = ns.unwrap_or("");
+    scopes.iter().rev().filter_map(|x| x.get(ns)).map(|x| &x[..]).next()
+          .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No namespace prefix bound"))
+}
+
+// Writes an element like fmt_elem, without allocating for the output,
+// escaping its text for a document of the given version
+fn write_elem<'a, W: Write + ?Sized>(elem: &'a Element, parent: Option<&Element>,
+                                     scopes: &mut Vec<&'a HashMap<String, String>>,
+                                     version: XmlVersion, w: &mut W) -> io::Result<()> {
+    scopes.push(&elem.prefixes);
+
+    // Do we need a prefix?
+    let prefix = if elem.ns != elem.default_ns {
+        Some(bound_prefix(scopes, elem.ns.as_deref())?)
+    } else {
+        None
+    };
+    match prefix {
+        Some(prefix) => write!(w, "<{}:{}", prefix, elem.name)?,
+        None => write!(w, "<{}", elem.name)?
+    }
+
+    // Do we need to set the default namespace ?
+    if !elem.attributes.iter().any(|((name, _), _)| name == "xmlns") {
+        match (parent, &elem.default_ns) {
+            // No parent, namespace is not empty
+            (None, Some(ns)) => write!(w, " xmlns='{}'", ns)?,
+            // Parent and child namespace differ
+            (Some(parent), ns) if parent.default_ns != *ns => {
+                write!(w, " xmlns='{}'", ns.as_deref().unwrap_or(""))?
+            }
+            _ => ()
+        }
+    }
+
+    for ((name, ns), value) in &elem.attributes {
+        match *ns {
+            Some(ref ns) => write!(w, " {}:{}='", bound_prefix(scopes, Some(ns))?, name)?,
+            None => write!(w, " {}='", name)?
+        }
+        write_escaped_as(w, value, version)?;
+        w.write_all(b"'")?;
+    }
+
+    if elem.children.is_empty() {
+        w.write_all(b"/>")?;
+    } else {
+        w.write_all(b">")?;
+        for child in &elem.children {
+            match *child {
+                Xml::ElementNode(ref child) => write_elem(child, Some(elem), scopes, version, w)?,
+                Xml::CharacterNode(ref data) => write_escaped_as(w, data, version)?,
+                ref o => write_node(o, w)?
+            }
+        }
+        match prefix {
+            Some(prefix) => write!(w, "</{}:{}>", prefix, elem.name)?,
+            None => write!(w, "</{}>", elem.name)?
+        }
+    }
+    scopes.pop();
+    Ok(())
+}
+
+// Writes a node like its Display implementation
+pub(crate) fn write_node<W: Write + ?Sized>(node: &Xml, w: &mut W) -> io::Result<()> {
+    match *node {
+        Xml::ElementNode(ref elem) => {
+            write_elem(elem, None, &mut Vec::new(), XmlVersion::Version10, w)
+        }
+        Xml::CharacterNode(ref data) => write_escaped_as(w, data, XmlVersion::Version10),
+        Xml::CDATANode(ref data) => write!(w, "<![CDATA[{}]]>", data),
+        Xml::CommentNode(ref data) => write!(w, "<!--{}-->", data),
+        Xml::PINode(ref data) => write!(w, "<?{}?>", data),
+        Xml::EntityRefNode(ref name) => write!(w, "&{};", name)
+    }
+}
+
+// Whether text contains characters only XML 1.1 can represent, i.e. control characters
+fn needs_xml11(text: &str) -> bool {
+    text.chars().any(|c| c < ' ' && !matches!(c, '\t' | '\n' | '\r'))
+}
+
+/// Characters XML 1.0 does not allow, such as most control characters, are written as is,
+/// making the output ill-formed. `write_to()` reports them as an error instead,
+/// and `to_document_string()` writes an XML 1.1 document representing them.
 impl fmt::Display for Element {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt_elem(self, None, &HashMap::new(), f)
@@ -119,6 +211,84 @@ impl<'a, 'b> Iterator for ChildElements<'a, 'b> {
     }
 }
 
+/// An iterator returning the `Event`s describing an `Element`, as returned by `Element::events()`
+pub struct ElementEvents<'a> {
+    root: Option<&'a Element>,
+    // The open elements, with their remaining children and the prefixes in scope
+    stack: Vec<(&'a Element, slice::Iter<'a, Xml>, HashMap<String, String>)>
+}
+
+// The prefix an element is written with, if it is not in the default namespace
+fn elem_prefix(elem: &Element, prefixes: &HashMap<String, String>) -> Option<String> {
+    if elem.ns != elem.default_ns {
+        elem.ns.as_ref().and_then(|ns| prefixes.get(ns)).cloned()
+    } else {
+        None
+    }
+}
+
+impl<'a> ElementEvents<'a> {
+    fn start(&mut self, elem: &'a Element) -> Event {
+        let (parent, mut prefixes) = match self.stack.last() {
+            Some((parent, _, prefixes)) => (Some(*parent), prefixes.clone()),
+            None => (None, HashMap::new())
+        };
+        prefixes.extend(elem.prefixes.clone());
+
+        // Declare the default namespace where the serialized element would
+        let mut attributes = Attributes::with_capacity(elem.attributes.len() + 1);
+        if !elem.attributes.iter().any(|((name, _), _)| name == "xmlns") {
+            match (parent, &elem.default_ns) {
+                (None, Some(ns)) => {
+                    attributes.insert(("xmlns".to_owned(), None), ns.clone());
+                }
+                (Some(parent), ns) if parent.default_ns != *ns => {
+                    attributes.insert(("xmlns".to_owned(), None), ns.clone().unwrap_or_default());
+                }
+                _ => ()
+            }
+        }
+        attributes.extend(elem.attributes.iter().map(|(key, value)| (key.clone(), value.clone())));
+
+        let prefix = elem_prefix(elem, &prefixes);
+        self.stack.push((elem, elem.children.iter(), prefixes));
+        Event::ElementStart(StartTag {
+            name: elem.name.clone(),
+            ns: elem.ns.clone(),
+            prefix,
+            attributes
+        })
+    }
+}
+
+impl<'a> Iterator for ElementEvents<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if let Some(root) = self.root.take() {
+            return Some(self.start(root));
+        }
+
+        let child = self.stack.last_mut()?.1.next();
+        Some(match child {
+            Some(Xml::ElementNode(elem)) => self.start(elem),
+            Some(Xml::CharacterNode(text)) => Event::Characters(text.clone()),
+            Some(Xml::CDATANode(text)) => Event::CDATA(text.clone()),
+            Some(Xml::CommentNode(text)) => Event::Comment(text.clone()),
+            Some(Xml::PINode(text)) => Event::PI(text.clone()),
+            Some(Xml::EntityRefNode(name)) => Event::EntityRef(name.clone()),
+            None => {
+                let (elem, _, prefixes) = self.stack.pop()?;
+                Event::ElementEnd(EndTag {
+                    name: elem.name.clone(),
+                    ns: elem.ns.clone(),
+                    prefix: elem_prefix(elem, &prefixes)
+                })
+            }
+        })
+    }
+}
+
 impl Element {
     /// Create a new `Element`, with specified name and namespace.
     /// Attributes are specified as a `Vec` of `(name, namespace, value)` tuples.
@@ -129,9 +299,9 @@ impl Element {
         prefixes.insert("http://www.w3.org/XML/1998/namespace".to_owned(), "xml".to_owned());
         prefixes.insert("http://www.w3.org/2000/xmlns/".to_owned(), "xmlns".to_owned());
 
-        let attributes: HashMap<_, _> = attrs.into_iter()
-                                             .map(|(name, ns, value)| ((name, ns), value))
-                                             .collect();
+        let attributes: Attributes = attrs.into_iter()
+                                          .map(|(name, ns, value)| ((name, ns), value))
+                                          .collect();
 
         Element {
             name: name,
@@ -143,6 +313,35 @@ impl Element {
         }
     }
 
+    /// Returns an iterator over the `Event`s describing the element, as a `Parser` would
+    /// return them for its serialization.
+    ///
+    /// Building an `Element` from the events with an `ElementBuilder` yields the same
+    /// element as parsing its serialization.
+    ///
+    /// ~~~
+    /// use xml::{Element, ElementBuilder};
+    ///
+    /// let elem: Element = "<a xmlns='urn:a'>x<b/></a>".parse().unwrap();
+    /// let mut builder = ElementBuilder::new();
+    /// let copy = elem.events().filter_map(|x| builder.handle_event(Ok(x))).next();
+    /// assert_eq!(copy, Some(Ok(elem)));
+    /// ~~~
+    pub fn events(&self) -> ElementEvents<'_> {
+        ElementEvents { root: Some(self), stack: Vec::new() }
+    }
+
+    /// Writes the element to `w`, producing the same output as its `Display` implementation.
+    ///
+    /// Returns the number of bytes written. Writing fails with an `io::ErrorKind::InvalidData`
+    /// error if no prefix is bound for a namespace used, or if the element contains characters
+    /// XML 1.0 does not allow, which `Display` writes as is.
+    pub fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<usize> {
+        let mut w = CountingWriter::new(w);
+        write_elem(self, None, &mut Vec::new(), XmlVersion::Version10, &mut w)?;
+        Ok(w.count)
+    }
+
     /// Returns the character and CDATA contained in the element.
     pub fn content_str(&self) -> String {
         let mut res = String::new();
@@ -157,10 +356,41 @@ impl Element {
         res
     }
 
+    /// Returns the XML version needed to represent the element.
+    ///
+    /// This is XML 1.1 if the element's character data or attribute values contain
+    /// control characters other than whitespace, which XML 1.0 does not allow.
+    pub fn required_version(&self) -> XmlVersion {
+        let xml11 = self.attributes.values().any(|v| needs_xml11(v))
+            || self.children.iter().any(|child| match *child {
+                Xml::ElementNode(ref elem) => elem.required_version() == XmlVersion::Version11,
+                Xml::CharacterNode(ref data) => needs_xml11(data),
+                _ => false
+            });
+        if xml11 {
+            XmlVersion::Version11
+        } else {
+            XmlVersion::Version10
+        }
+    }
+
+    /// Serialises the element as a document, starting with an XML declaration.
+    /// The declaration states the version returned by `required_version()`,
+    /// for XML 1.1 control characters are written as character references.
+    ///
+    /// Fails with an `io::ErrorKind::InvalidData` error if no prefix is bound for a namespace
+    /// used, or the element contains characters no version of XML allows, such as `\0`.
+    pub fn to_document_string(&self) -> io::Result<String> {
+        let version = self.required_version();
+        let mut out = format!("<?xml version='{}'?>", version).into_bytes();
+        write_elem(self, None, &mut Vec::new(), version, &mut out)?;
+        Ok(String::from_utf8(out).expect("Serialized XML is valid UTF-8"))
+    }
+
     /// Gets an attribute with the specified name and namespace. When an attribute with the
     /// specified name does not exist `None` is returned.
     pub fn get_attribute<'a>(&'a self, name: &str, ns: Option<&str>) -> Option<&'a str> {
-        self.attributes.get(&(name.to_owned(), ns.map(|x| x.to_owned()))).map(|x| &x[..])
+        self.attributes.get_by_name(name, ns).map(|x| &x[..])
     }
 
     /// Sets the attribute with the specified name and namespace.
@@ -173,7 +403,7 @@ impl Element {
     /// Remove the attribute with the specified name and namespace.
     /// Returns the original value.
     pub fn remove_attribute(&mut self, name: &str, ns: Option<&str>) -> Option<String> {
-        self.attributes.remove(&(name.to_owned(), ns.map(|x| x.to_owned())))
+        self.attributes.remove_by_name(name, ns)
     }
 
     /// Gets the first child `Element` with the specified name and namespace. When no child
@@ -252,6 +482,11 @@ impl FromStr for Element {
 #[cfg(test)]
 mod tests {
     use super::Element;
+    use element_builder::ElementBuilder;
+    use parser::Parser;
+    use source::EventSource;
+    use std::io;
+    use Xml;
 
     #[test]
     fn test_get_children() {
@@ -261,10 +496,120 @@ mod tests {
                         &Element::new("b".to_owned(), None, vec![])]);
     }
 
+    #[test]
+    fn test_to_document_string() {
+        let mut elem = Element::new("a".to_owned(), None, vec![]);
+        elem.text("x\ty".to_owned());
+        assert_eq!(elem.to_document_string().unwrap(), "<?xml version='1.0'?><a>x\ty</a>");
+
+        let mut elem = Element::new("a".to_owned(), None, vec![]);
+        elem.text("x\ty\u{85}\u{2028}".to_owned());
+        assert_eq!(elem.to_document_string().unwrap(),
+                   "<?xml version='1.0'?><a>x\ty\u{85}\u{2028}</a>");
+        let reparsed: Element = elem.to_document_string().unwrap().parse().unwrap();
+        assert_eq!(reparsed, elem);
+
+        elem.tag(Element::new("b".to_owned(), None, vec![])).text("\u{1}".to_owned());
+        assert_eq!(elem.to_document_string().unwrap(),
+                   "<?xml version='1.1'?><a>x\ty&#x85;&#x2028;<b>&#x1;</b></a>");
+        assert_eq!(elem.to_string(), "<a>x\ty\u{85}\u{2028}<b>\u{1}</b></a>");
+        let reparsed: Element = elem.to_document_string().unwrap().parse().unwrap();
+        assert_eq!(reparsed, elem);
+
+        elem.text("\u{0}".to_owned());
+        assert_eq!(elem.to_document_string().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let elem = Element::new("a".to_owned(), None,
+                                vec![("x".to_owned(), Some("urn:x".to_owned()), "1".to_owned())]);
+        assert_eq!(elem.to_document_string().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn test_get_child() {
         let elem: Element = "<a><b/><c/><b/></a>".parse().unwrap();
         assert_eq!(elem.get_child("b", None),
                    Some(&Element::new("b".to_owned(), None, vec![])));
     }
+
+    #[test]
+    fn test_events() {
+        let inputs = [
+            "<a/>",
+            "<a xmlns='urn:a' xmlns:b='urn:b'><b:c x='1' b:y='&amp;'>t<![CDATA[<d>]]>\
+             <!--c--><?pi x?></b:c><e xmlns=''/></a>",
+            "<x:a xmlns:x='urn:x'><b><x:c/></b>text</x:a>"
+        ];
+        for input in &inputs {
+            let elem: Element = input.parse().unwrap();
+            let mut p = Parser::new();
+            p.feed_str(&elem.to_string());
+            let expected: Vec<_> = p.map(|x| x.unwrap()).collect();
+            assert_eq!(elem.events().collect::<Vec<_>>(), expected);
+
+            let mut builder = ElementBuilder::new();
+            assert_eq!(builder.build_from(&mut elem.events()), Some(Ok(elem)));
+        }
+    }
+
+    #[test]
+    fn test_events_constructed() {
+        let mut elem = Element::new("a".to_owned(), Some("urn:a".to_owned()), vec![]);
+        elem.tag(Element::new("b".to_owned(), None, vec![("x".to_owned(), None, "1".to_owned())]))
+            .text("t".to_owned());
+        let mut events = elem.events();
+        assert_eq!(events.position(), None);
+
+        let mut builder = ElementBuilder::new();
+        let built = builder.build_from(&mut events).unwrap().unwrap();
+        assert_eq!(built, elem.to_string().parse().unwrap());
+        assert_eq!(built.to_string(), "<a xmlns='urn:a'><b xmlns='' x='1'>t</b></a>");
+        assert!(events.next_event().is_none());
+    }
+
+    #[test]
+    fn test_write_to() {
+        let inputs = [
+            "<a xmlns='urn:a' xmlns:b='urn:b'><b:c x='&apos;1&apos;' b:y='&amp;'>t &lt; \
+             <![CDATA[<d>]]><!--c--><?pi x?></b:c><e xmlns=''/></a>",
+            "<x:a xmlns:x='urn:x' xml:lang='en'><b><x:c/></b>\u{e9}</x:a>"
+        ];
+        for input in &inputs {
+            let elem: Element = input.parse().unwrap();
+            let mut out = Vec::new();
+            let written = elem.write_to(&mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), elem.to_string());
+            assert_eq!(written, elem.to_string().len());
+        }
+
+        let mut elem = Element::new("a".to_owned(), Some("urn:a".to_owned()), vec![]);
+        elem.tag(Element::new("b".to_owned(), None, vec![])).text("\u{e9}".to_owned());
+        let mut out: Vec<u8> = Vec::new();
+        assert_eq!(elem.write_to(&mut out).unwrap(), out.len());
+        assert_eq!(String::from_utf8(out).unwrap(), elem.to_string());
+
+        let node = Xml::CharacterNode("<&>".to_owned());
+        let mut out = Vec::new();
+        assert_eq!(node.write_to(&mut out).unwrap(), 13);
+        assert_eq!(out, b"&lt;&amp;&gt;");
+    }
+
+    #[test]
+    fn test_write_to_errors() {
+        let elem = Element::new("a".to_owned(), None,
+                                vec![("x".to_owned(), Some("urn:x".to_owned()), "1".to_owned())]);
+        let err = elem.write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut elem = Element::new("a".to_owned(), None, vec![]);
+        elem.text("\u{1}".to_owned());
+        let err = elem.write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = Xml::CharacterNode("\u{FFFF}".to_owned()).write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let elem: Element = "<a>text</a>".parse().unwrap();
+        let mut buf = [0u8; 4];
+        let err = elem.write_to(&mut &mut buf[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(&buf, b"<a>t");
+    }
 }
@@ -5,10 +5,12 @@
 // Please see the COPYING file for more information.
 
 use super::{Event, Xml, Element, StartTag, EndTag};
-use parser::ParserError;
+use parser::{Parser, ParserError};
+use source::{EventSource, SourceError};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::mem;
 
 #[derive(PartialEq, Debug, Clone)]
 /// The structure returned for errors encountered while building an `Element`
@@ -65,10 +67,16 @@ impl From<ParserError> for BuilderError {
 ///     println!("{}", result.unwrap());
 /// }
 /// ~~~
+#[derive(Clone)]
 pub struct ElementBuilder {
     stack: Vec<Element>,
     default_ns: Vec<Option<String>>,
-    prefixes: HashMap<String, String>
+    base_ns: Option<String>,
+    prefixes: HashMap<String, String>,
+    mappings: Vec<(Option<String>, String)>,
+    lenient: bool,
+    html: bool,
+    namespace_aware: bool
 }
 
 impl ElementBuilder {
@@ -80,7 +88,77 @@ impl ElementBuilder {
         ElementBuilder {
             stack: Vec::new(),
             default_ns: Vec::new(),
-            prefixes: prefixes
+            base_ns: None,
+            prefixes: prefixes,
+            mappings: Vec::new(),
+            lenient: false,
+            html: false,
+            namespace_aware: true
+        }
+    }
+
+    /// Enables or disables lenient mode.
+    ///
+    /// In lenient mode an end tag closes all elements left open inside the element it ends,
+    /// and end tags not matching any open element are ignored.
+    /// Elements still open at the end of the input can be closed using `finish()`.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Enables or disables HTML mode, for use with a `Parser` in HTML mode.
+    ///
+    /// In HTML mode `xmlns` attributes are not treated as namespace declarations,
+    /// and elements are closed as in lenient mode.
+    pub fn set_html(&mut self, html: bool) {
+        self.html = html;
+    }
+
+    /// Enables or disables namespace processing, for use with a `Parser` without it.
+    ///
+    /// Without namespace processing `xmlns` attributes are not treated as namespace
+    /// declarations, and an element's name includes its prefix, e.g. `foo:bar`.
+    pub fn set_namespace_aware(&mut self, namespace_aware: bool) {
+        self.namespace_aware = namespace_aware;
+    }
+
+    /// Signals the end of the input.
+    ///
+    /// Returns `None` if no element is open.
+    /// In lenient mode all open elements are closed, and the root element is returned
+    /// as `Some(Ok(elem))`. Otherwise `Some(Err(BuilderError::ImproperNesting))` is returned.
+    pub fn finish(&mut self) -> Option<Result<Element, BuilderError>> {
+        if self.stack.is_empty() {
+            return None;
+        }
+        if !self.lenient && !self.html {
+            return Some(Err(BuilderError::ImproperNesting));
+        }
+        loop {
+            if let Some(elem) = self.close_element() {
+                return Some(Ok(elem));
+            }
+        }
+    }
+
+    // The name of an element, which includes its prefix without namespace processing
+    fn element_name(&self, prefix: Option<String>, name: String) -> String {
+        match prefix {
+            Some(ref prefix) if !self.namespace_aware => format!("{}:{}", prefix, name),
+            _ => name
+        }
+    }
+
+    // Close the innermost open element, returning it if it is the root element
+    fn close_element(&mut self) -> Option<Element> {
+        let elem = self.stack.pop().expect("Internal error: No open element");
+        self.default_ns.pop();
+        match self.stack.last_mut() {
+            Some(e) => {
+                e.children.push(Xml::ElementNode(elem));
+                None
+            }
+            None => Some(elem)
         }
     }
 
@@ -91,9 +169,23 @@ impl ElementBuilder {
 
     /// Set the default namespace
     pub fn set_default_ns(&mut self, ns: String) {
+        self.base_ns = Some(ns.clone());
         self.default_ns = vec![Some(ns)];
     }
 
+    /// Discards any partially built element, e.g. when an XMPP stream is restarted.
+    ///
+    /// Prefixes bound by `define_prefix()`, the default namespace and other
+    /// configuration are kept.
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.mappings.clear();
+        self.default_ns.clear();
+        if let Some(ref ns) = self.base_ns {
+            self.default_ns.push(Some(ns.clone()));
+        }
+    }
+
     /// Let the builder process an `Event` to ultimately build an `Element`.
     ///
     /// While no root element has been finished `None` is returned.
@@ -111,7 +203,23 @@ impl ElementBuilder {
                     elem.children.push(Xml::PINode(cont));
                 }
             }
-            Event::ElementStart(StartTag { name, ns, prefix: _, attributes }) => {
+            Event::StartPrefixMapping { prefix, uri } => self.mappings.push((prefix, uri)),
+            Event::EndPrefixMapping { .. }
+            | Event::StartDocument
+            | Event::EndDocument => (),
+            Event::ElementStart(StartTag { name, ns, prefix, mut attributes }) => {
+                // Restore namespace declarations removed from the attributes
+                for (prefix, uri) in self.mappings.drain(..) {
+                    let key = match prefix {
+                        None => ("xmlns".to_owned(), None),
+                        Some(prefix) => (prefix, Some("http://www.w3.org/2000/xmlns/".to_owned()))
+                    };
+                    if !attributes.contains_key(&key) {
+                        attributes.insert(key, uri);
+                    }
+                }
+
+                let name = self.element_name(prefix, name);
                 let mut elem = Element {
                     name: name.clone(),
                     ns: ns.clone(),
@@ -125,26 +233,39 @@ impl ElementBuilder {
                     self.default_ns.push(default)
                 }
 
-                for (&(ref name, ref ns), value) in &elem.attributes {
-                    if ns.is_none() && name == "xmlns" {
-                        self.default_ns.pop();
-                        if value.is_empty() {
-                            self.default_ns.push(None);
-                        } else {
-                            self.default_ns.push(Some(value.clone()));
+                // xmlns attributes are no namespace declarations in HTML
+                if !self.html && self.namespace_aware {
+                    for (&(ref name, ref ns), value) in &elem.attributes {
+                        if ns.is_none() && name == "xmlns" {
+                            self.default_ns.pop();
+                            if value.is_empty() {
+                                self.default_ns.push(None);
+                            } else {
+                                self.default_ns.push(Some(value.clone()));
+                            }
+                            continue;
                         }
-                        continue;
-                    }
 
-                    if ns.as_ref().map_or(false, |x| x == "http://www.w3.org/2000/xmlns/") {
-                        elem.prefixes.insert(value.clone(), name.clone());
+                        if ns.as_ref().map_or(false, |x| x == "http://www.w3.org/2000/xmlns/") {
+                            elem.prefixes.insert(value.clone(), name.clone());
+                        }
                     }
                 }
                 elem.default_ns = self.default_ns.last().unwrap_or(&None).clone();
 
                 self.stack.push(elem);
             }
-            Event::ElementEnd(EndTag { name, ns, prefix: _ }) => {
+            Event::ElementEnd(EndTag { name, ns, prefix }) => {
+                let name = self.element_name(prefix, name);
+                if self.lenient || self.html {
+                    let idx = self.stack.iter().rposition(|e| e.name == name && e.ns == ns);
+                    match idx {
+                        Some(idx) => while self.stack.len() > idx + 1 {
+                            self.close_element();
+                        },
+                        None => return None
+                    }
+                }
                 let elem = match self.stack.pop() {
                     Some(elem) => elem,
                     None => return Some(Err(BuilderError::ImproperNesting))
@@ -159,7 +280,8 @@ impl ElementBuilder {
                     }
                 }
             }
-            Event::Characters(chars) => {
+            Event::Characters(chars)
+            | Event::Whitespace(chars) => {
                 if let Some(elem) = self.stack.last_mut() {
                     elem.children.push(Xml::CharacterNode(chars));
                 }
@@ -174,7 +296,388 @@ impl ElementBuilder {
                     elem.children.push(Xml::CommentNode(cont));
                 }
             }
+            Event::EntityRef(name) => {
+                if let Some(elem) = self.stack.last_mut() {
+                    elem.children.push(Xml::EntityRefNode(name));
+                }
+            }
         }
         None
     }
+
+    /// Let the builder process the `Event`s of an `EventSource` until an `Element`
+    /// is complete.
+    ///
+    /// Returns `Some(Ok(elem))` for the finished element, or `None` if the source
+    /// ran out of events first. Upon Error `Some(Err(err))` is returned.
+    pub fn build_from<S>(&mut self, source: &mut S) -> Option<Result<Element, SourceError<S::Error>>>
+        where S: EventSource + ?Sized
+    {
+        while let Some(event) = source.next_event() {
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => return Some(Err(SourceError::Source(err)))
+            };
+            if let Some(result) = self.handle_event(Ok(event)) {
+                return Some(result.map_err(SourceError::Builder));
+            }
+        }
+        None
+    }
+}
+
+// The namespace declarations in scope at the current position in a document,
+// used to build elements starting there
+#[derive(Default)]
+pub(crate) struct NamespaceContext {
+    scopes: Vec<Vec<(Option<String>, String)>>,
+    mappings: Vec<(Option<String>, String)>
+}
+
+impl NamespaceContext {
+    pub(crate) fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::StartPrefixMapping { ref prefix, ref uri } => {
+                self.mappings.push((prefix.clone(), uri.clone()));
+            }
+            Event::ElementStart(ref tag) => {
+                let mut decls = mem::take(&mut self.mappings);
+                for ((name, ns), value) in &tag.attributes {
+                    match *ns {
+                        None if name == "xmlns" => decls.push((None, value.clone())),
+                        Some(ref ns) if ns == "http://www.w3.org/2000/xmlns/" => {
+                            decls.push((Some(name.clone()), value.clone()))
+                        }
+                        _ => ()
+                    }
+                }
+                self.scopes.push(decls);
+            }
+            Event::ElementEnd(_) => {
+                self.scopes.pop();
+            }
+            _ => ()
+        }
+    }
+
+    // Let builder know the namespaces in scope
+    pub(crate) fn prepare(&self, builder: &mut ElementBuilder) {
+        for (prefix, ns) in self.scopes.iter().flat_map(|x| x.iter()) {
+            match *prefix {
+                Some(ref prefix) => builder.define_prefix(prefix.clone(), ns.clone()),
+                None if ns.is_empty() => builder.default_ns = vec![None],
+                None => builder.default_ns = vec![Some(ns.clone())]
+            }
+        }
+        builder.mappings.extend(self.mappings.iter().cloned());
+    }
+}
+
+#[derive(PartialEq, Debug)]
+/// Events returned by a `StreamBuilder`
+pub enum StreamEvent {
+    /// The root element was opened. The `Element` has its attributes and namespace
+    /// declarations, but no children.
+    StreamStart(Element),
+    /// A child of the root element was completed
+    Stanza(Element),
+    /// The root element was closed
+    StreamEnd
+}
+
+/// A Stream Builder, building the children of the root element as soon as they are complete
+///
+/// This is meant for XMPP-like streams, where the root element stays open while its
+/// children are exchanged. The opening of the root element is reported right away,
+/// each of its children is returned as an `Element` carrying the namespaces declared by
+/// the root element, and content between the children is dropped.
+///
+/// ~~~
+/// use xml::{Parser, StreamBuilder, StreamEvent};
+///
+/// let mut parser = Parser::new();
+/// let mut builder = StreamBuilder::new();
+///
+/// parser.feed_str("<stream:stream xmlns:stream='http://etherx.jabber.org/streams' \
+///                  xmlns='jabber:client'><message/>");
+/// for event in parser.filter_map(|event| builder.handle_event(event)) {
+///     match event.unwrap() {
+///         StreamEvent::StreamStart(root) => println!("Stream opened: {}", root),
+///         StreamEvent::Stanza(elem) => println!("Stanza: {}", elem),
+///         StreamEvent::StreamEnd => println!("Stream closed")
+///     }
+/// }
+/// ~~~
+pub struct StreamBuilder {
+    builder: ElementBuilder
+}
+
+impl StreamBuilder {
+    /// Returns a new `StreamBuilder`
+    pub fn new() -> StreamBuilder {
+        StreamBuilder { builder: ElementBuilder::new() }
+    }
+
+    /// Returns a `StreamBuilder` building elements using `builder`
+    pub fn with_builder(builder: ElementBuilder) -> StreamBuilder {
+        StreamBuilder { builder }
+    }
+
+    /// Returns a mutable reference to the `ElementBuilder` used, e.g. to configure it
+    pub fn builder_mut(&mut self) -> &mut ElementBuilder {
+        &mut self.builder
+    }
+
+    /// Discards the open root element, e.g. when an XMPP stream is restarted
+    pub fn reset(&mut self) {
+        self.builder.reset();
+    }
+
+    /// Let the builder process an `Event`.
+    ///
+    /// Returns `Some(Ok(event))` when the root element is opened or closed, or one of
+    /// its children is completed, `None` otherwise. Upon Error `Some(Err(err))` is returned.
+    pub fn handle_event(&mut self, e: Result<Event, ParserError>)
+                        -> Option<Result<StreamEvent, BuilderError>> {
+        let opened = self.builder.stack.is_empty() && matches!(e, Ok(Event::ElementStart(_)));
+        let closed = matches!(e, Ok(Event::ElementEnd(_)));
+        match self.builder.handle_event(e) {
+            Some(Ok(_)) => return Some(Ok(StreamEvent::StreamEnd)),
+            Some(Err(err)) => return Some(Err(err)),
+            None => ()
+        }
+
+        let stack = &mut self.builder.stack;
+        if stack.len() != 1 {
+            return None;
+        }
+        let root = &mut stack[0];
+        if opened {
+            return Some(Ok(StreamEvent::StreamStart(root.clone())));
+        }
+        let last = root.children.pop();
+        root.children.clear();
+        match last {
+            Some(Xml::ElementNode(mut elem)) if closed => {
+                for (ns, prefix) in &root.prefixes {
+                    if !elem.prefixes.contains_key(ns) {
+                        elem.prefixes.insert(ns.clone(), prefix.clone());
+                    }
+                }
+                Some(Ok(StreamEvent::Stanza(elem)))
+            }
+            _ => None
+        }
+    }
+}
+
+impl Default for StreamBuilder {
+    fn default() -> StreamBuilder {
+        StreamBuilder::new()
+    }
+}
+
+/// An iterator over the root elements of the documents parsed by a `Parser`,
+/// as returned by `Parser::documents()`
+pub struct Documents<'a> {
+    parser: &'a mut Parser,
+    builder: ElementBuilder
+}
+
+impl<'a> Documents<'a> {
+    pub(crate) fn new(parser: &'a mut Parser, builder: ElementBuilder) -> Documents<'a> {
+        Documents { parser, builder }
+    }
+}
+
+impl<'a> Iterator for Documents<'a> {
+    type Item = Result<Element, BuilderError>;
+
+    fn next(&mut self) -> Option<Result<Element, BuilderError>> {
+        loop {
+            let event = (&mut *self.parser).next()?;
+            if let Some(result) = self.builder.handle_event(event) {
+                return Some(result);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod builder_tests {
+    use super::{BuilderError, ElementBuilder, StreamBuilder, StreamEvent};
+    use parser::Parser;
+
+    #[test]
+    fn test_improper_nesting() {
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        p.feed_str("<a><b></a></b>");
+        assert_eq!(p.filter_map(|x| e.handle_event(x)).next(),
+                   Some(Err(BuilderError::ImproperNesting)));
+    }
+
+    #[test]
+    fn test_lenient_finish() {
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        e.set_lenient(true);
+        p.feed_str("<a><b>Hello<c></b>World<d/>");
+        assert_eq!(p.filter_map(|x| e.handle_event(x)).next(), None);
+        let elem = e.finish().unwrap().unwrap();
+        assert_eq!(format!("{}", elem), "<a><b>Hello<c/></b>World<d/></a>");
+        assert_eq!(e.finish(), None);
+    }
+
+    #[test]
+    fn test_strict_finish() {
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        p.feed_str("<a><b>");
+        assert_eq!(p.filter_map(|x| e.handle_event(x)).next(), None);
+        assert_eq!(e.finish(), Some(Err(BuilderError::ImproperNesting)));
+    }
+
+    #[test]
+    fn test_lenient_unbound_prefix_roundtrip() {
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        p.set_lenient(true);
+        p.feed_str("<foo:a><foo:b/></foo:a>");
+        let elem = p.filter_map(|x| e.handle_event(x)).next().unwrap().unwrap();
+        assert_eq!(elem.ns, Some("urn:rustyxml:unbound:foo".to_owned()));
+        let mut p = Parser::new();
+        p.feed_str(&format!("{}", elem));
+        assert!((&mut p).all(|x| x.is_ok()));
+    }
+
+    #[test]
+    fn test_html() {
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        p.set_html(true);
+        e.set_html(true);
+        p.feed_str("<html xmlns='http://www.w3.org/1999/xhtml'><body><p>Hi<br>there</body></html>");
+        let elem = p.filter_map(|x| e.handle_event(x)).next().unwrap().unwrap();
+        assert_eq!(format!("{}", elem),
+                   "<html xmlns='http://www.w3.org/1999/xhtml'><body><p>Hi<br/>there</p></body></html>");
+    }
+
+    #[test]
+    fn test_namespace_unaware_roundtrip() {
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        p.set_namespace_aware(false);
+        e.set_namespace_aware(false);
+        p.feed_str("<a:b xmlns='urn:x' c:d='1'><e:f/></a:b>");
+        let elem = p.filter_map(|x| e.handle_event(x)).next().unwrap().unwrap();
+        assert_eq!(elem.name, "a:b");
+        assert_eq!(elem.ns, None);
+        assert_eq!(elem.get_attribute("c:d", None), Some("1"));
+        assert_eq!(elem.get_child("e:f", None).map(|x| &x.name[..]), Some("e:f"));
+
+        assert_eq!(format!("{}", elem), "<a:b xmlns='urn:x' c:d='1'><e:f/></a:b>");
+    }
+
+    #[test]
+    fn test_entity_ref_roundtrip() {
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        p.set_preserve_entity_refs(true);
+        let input = "<a>&nbsp;x&amp;y&copy;&ext.ent;</a>";
+        p.feed_str(input);
+        let elem = p.filter_map(|x| e.handle_event(x)).next().unwrap().unwrap();
+        assert_eq!(format!("{}", elem), input);
+        assert_eq!(elem.content_str(), "x&y");
+    }
+
+    #[test]
+    fn test_prefix_mappings() {
+        let input = "<a xmlns='urn:a' xmlns:b='urn:b' x='1'><b:c b:y='2'/></a>";
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        p.set_report_prefix_mappings(true);
+        p.set_strip_namespace_declarations(true);
+        p.feed_str(input);
+        let elem = p.filter_map(|x| e.handle_event(x)).next().unwrap().unwrap();
+        assert_eq!(elem, input.parse().unwrap());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut p = Parser::new();
+        let mut e = ElementBuilder::new();
+        e.set_default_ns("jabber:client".to_owned());
+        p.feed_str("<a><b>");
+        assert_eq!(p.filter_map(|x| e.handle_event(x)).next(), None);
+        p.reset(false);
+        e.reset();
+        p.feed_str("<c xmlns='jabber:client'/>");
+        let elem = p.filter_map(|x| e.handle_event(x)).next().unwrap().unwrap();
+        assert_eq!(elem.name, "c");
+        assert_eq!(format!("{}", elem), "<c xmlns='jabber:client'/>");
+    }
+
+    #[test]
+    fn test_documents() {
+        let mut p = Parser::new();
+        p.set_multiple_documents(true);
+        p.feed_str("<?xml version='1.0'?><log n='1'/>\n<?xml version='1.0'?><log n='2'><x/></log>");
+        let docs: Vec<_> = p.documents().map(|x| x.unwrap()).collect();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].get_attribute("n", None), Some("1"));
+        assert_eq!(docs[1].get_attribute("n", None), Some("2"));
+    }
+
+    #[test]
+    fn test_stream_builder() {
+        let mut p = Parser::new();
+        let mut b = StreamBuilder::new();
+        p.feed_str(include_str!("../data/test_xmpp.xml"));
+        let events: Vec<_> = p.filter_map(|x| b.handle_event(x)).map(|x| x.unwrap()).collect();
+        assert_eq!(events.len(), 5);
+
+        let root = match events[0] {
+            StreamEvent::StreamStart(ref root) => root,
+            ref e => panic!("Unexpected event: {:?}", e)
+        };
+        assert_eq!(root.name, "stream");
+        assert_eq!(root.get_attribute("to", None), Some("example.com"));
+        assert!(root.children.is_empty());
+
+        let names: Vec<_> = events[1..4].iter().map(|e| match *e {
+            StreamEvent::Stanza(ref elem) => format!("{}", elem),
+            ref e => panic!("Unexpected event: {:?}", e)
+        }).map(|x| x.split([' ', '>']).next().unwrap().to_owned()).collect();
+        assert_eq!(names, vec!["<message", "<iq", "<stream:error"]);
+
+        match events[1] {
+            StreamEvent::Stanza(ref elem) => {
+                assert_eq!(elem.ns, Some("jabber:client".to_owned()));
+                assert_eq!(elem.get_child("body", Some("jabber:client")).unwrap().content_str(),
+                           "Hello");
+            }
+            ref e => panic!("Unexpected event: {:?}", e)
+        }
+        assert_eq!(events[4], StreamEvent::StreamEnd);
+    }
+
+    #[test]
+    fn test_stream_builder_incremental() {
+        let mut p = Parser::new();
+        let mut b = StreamBuilder::new();
+        p.feed_str("<stream:stream xmlns:stream='urn:s' xmlns='jabber:client'><iq");
+        assert!(matches!(p.filter_map(|x| b.handle_event(x)).next(),
+                         Some(Ok(StreamEvent::StreamStart(_)))));
+        p.feed_str("/> <presence/>");
+        match p.filter_map(|x| b.handle_event(x)).next() {
+            Some(Ok(StreamEvent::Stanza(elem))) => {
+                assert_eq!(format!("{}", elem), "<iq xmlns='jabber:client'/>")
+            }
+            e => panic!("Unexpected result: {:?}", e)
+        }
+        assert!(p.filter_map(|x| b.handle_event(x)).next().is_some());
+        p.feed_str("</stream:stream>");
+        assert_eq!(p.filter_map(|x| b.handle_event(x)).next(), Some(Ok(StreamEvent::StreamEnd)));
+    }
 }
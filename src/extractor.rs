@@ -0,0 +1,246 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use super::{Event, Element};
+use element_builder::{BuilderError, ElementBuilder, NamespaceContext};
+use parser::ParserError;
+use std::collections::HashMap;
+
+// A step of a path pattern
+struct Step {
+    // Whether the step matches at any depth below the previous one (`//`)
+    descendant: bool,
+    // The namespace to match, `None` for any namespace
+    ns: Option<Option<String>>,
+    // The name to match, `None` for any name
+    name: Option<String>
+}
+
+impl Step {
+    fn matches(&self, elem: &(Option<String>, String)) -> bool {
+        self.ns.as_ref().is_none_or(|ns| *ns == elem.0)
+            && self.name.as_ref().is_none_or(|name| *name == elem.1)
+    }
+}
+
+// Whether the path of open elements matches the steps
+fn matches(steps: &[Step], path: &[(Option<String>, String)]) -> bool {
+    let (step, steps) = match steps.split_first() {
+        Some(split) => split,
+        None => return path.is_empty()
+    };
+    if step.descendant {
+        (0..path.len()).any(|i| step.matches(&path[i]) && matches(steps, &path[i + 1..]))
+    } else {
+        !path.is_empty() && step.matches(&path[0]) && matches(steps, &path[1..])
+    }
+}
+
+/// A Path Extractor, building the `Element`s matching a path pattern from `Event`s
+///
+/// Patterns are a list of element names separated by `/`, starting at the root element.
+/// Names separated by `//` instead match at any depth, `*` matches any name.
+/// Names may have a prefix bound to a namespace by `define_prefix()`, unprefixed names
+/// only match elements without a namespace. For example `/atom:feed/atom:entry`,
+/// `//item` or `/root//*`.
+///
+/// Only the elements matching the pattern are built, so memory use depends on the size
+/// of a single match, not that of the document. Matches within other matches are
+/// returned as part of the outer match.
+///
+/// ~~~
+/// use xml::{Parser, PathExtractor};
+///
+/// let mut p = Parser::new();
+/// p.feed_str("<feed xmlns='http://www.w3.org/2005/Atom'>\
+///               <title>Example</title>\
+///               <entry><title>First</title></entry>\
+///               <entry><title>Second</title></entry>\
+///             </feed>");
+///
+/// let mut extractor = PathExtractor::new();
+/// extractor.define_prefix("atom", "http://www.w3.org/2005/Atom");
+/// extractor.set_pattern("/atom:feed/atom:entry").unwrap();
+/// let entries: Vec<_> = p.filter_map(|x| extractor.handle_event(x)).collect();
+/// assert_eq!(entries.len(), 2);
+/// ~~~
+pub struct PathExtractor {
+    prefixes: HashMap<String, String>,
+    steps: Vec<Step>,
+    builder: ElementBuilder,
+    // The elements open outside of a match
+    path: Vec<(Option<String>, String)>,
+    context: NamespaceContext,
+    current: Option<ElementBuilder>
+}
+
+impl PathExtractor {
+    /// Returns a new `PathExtractor`, matching nothing until a pattern is set
+    pub fn new() -> PathExtractor {
+        PathExtractor {
+            prefixes: HashMap::new(),
+            steps: Vec::new(),
+            builder: ElementBuilder::new(),
+            path: Vec::new(),
+            context: NamespaceContext::default(),
+            current: None
+        }
+    }
+
+    /// Binds a prefix to a namespace, for use in patterns
+    pub fn define_prefix(&mut self, prefix: &str, ns: &str) {
+        self.prefixes.insert(prefix.to_owned(), ns.to_owned());
+    }
+
+    /// Sets the pattern elements have to match.
+    ///
+    /// Upon error the previous pattern is kept and `Err(name)` is returned,
+    /// with the invalid part of the pattern.
+    pub fn set_pattern(&mut self, pattern: &str) -> Result<(), String> {
+        if !pattern.starts_with('/') {
+            return Err(pattern.to_owned());
+        }
+        let mut steps = Vec::new();
+        let mut descendant = false;
+        for part in pattern[1..].split('/') {
+            if part.is_empty() {
+                if descendant {
+                    return Err(pattern.to_owned());
+                }
+                descendant = true;
+                continue;
+            }
+            let (ns, name) = match part.split_once(':') {
+                Some((prefix, name)) => match self.prefixes.get(prefix) {
+                    Some(ns) => (Some(Some(ns.clone())), name),
+                    None => return Err(part.to_owned())
+                },
+                None if part == "*" => (None, part),
+                None => (Some(None), part)
+            };
+            let name = if name == "*" { None } else { Some(name.to_owned()) };
+            steps.push(Step { descendant, ns, name });
+            descendant = false;
+        }
+        if descendant || steps.is_empty() {
+            return Err(pattern.to_owned());
+        }
+        self.steps = steps;
+        Ok(())
+    }
+
+    /// Returns a mutable reference to the `ElementBuilder` matches are built with,
+    /// e.g. to configure it to match the `Parser`
+    pub fn builder_mut(&mut self) -> &mut ElementBuilder {
+        &mut self.builder
+    }
+
+    /// Let the extractor process an `Event`.
+    ///
+    /// Returns `Some(Ok(elem))` when an element matching the pattern is complete,
+    /// `None` otherwise. Upon Error `Some(Err(err))` is returned.
+    pub fn handle_event(&mut self, e: Result<Event, ParserError>)
+                        -> Option<Result<Element, BuilderError>> {
+        let event = match e {
+            Ok(event) => event,
+            Err(err) => return Some(Err(From::from(err)))
+        };
+
+        if self.current.is_none() {
+            match event {
+                Event::ElementStart(ref tag) => {
+                    self.path.push((tag.ns.clone(), tag.name.clone()));
+                    if matches(&self.steps, &self.path) {
+                        self.path.pop();
+                        let mut builder = self.builder.clone();
+                        self.context.prepare(&mut builder);
+                        self.current = Some(builder);
+                    }
+                }
+                Event::ElementEnd(_) => {
+                    self.path.pop();
+                }
+                _ => ()
+            }
+        }
+        self.context.handle_event(&event);
+
+        let result = self.current.as_mut()?.handle_event(Ok(event));
+        if result.is_some() {
+            self.current = None;
+        }
+        result
+    }
+}
+
+impl Default for PathExtractor {
+    fn default() -> PathExtractor {
+        PathExtractor::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathExtractor;
+    use parser::Parser;
+    use Element;
+
+    fn extract(pattern: &str, input: &str) -> Vec<String> {
+        let mut p = Parser::new();
+        p.feed_str(input);
+        let mut e = PathExtractor::new();
+        e.define_prefix("a", "urn:a");
+        e.set_pattern(pattern).unwrap();
+        p.filter_map(|x| e.handle_event(x)).map(|x| format!("{}", x.unwrap())).collect()
+    }
+
+    #[test]
+    fn test_child_steps() {
+        let input = "<a:feed xmlns:a='urn:a'><a:entry>1</a:entry><entry>2</entry>\
+                     <x><a:entry>3</a:entry></x><a:entry>4</a:entry></a:feed>";
+        assert_eq!(extract("/a:feed/a:entry", input),
+                   vec!["<a:entry>1</a:entry>", "<a:entry>4</a:entry>"]);
+        assert_eq!(extract("/a:feed/entry", input), vec!["<entry>2</entry>"]);
+        assert_eq!(extract("/a:feed/*", input).len(), 4);
+        assert_eq!(extract("/*/a:*", input).len(), 2);
+        assert_eq!(extract("/a:feed/x/a:entry", input), vec!["<a:entry>3</a:entry>"]);
+    }
+
+    #[test]
+    fn test_descendant_steps() {
+        let input = "<rss><channel><item>1</item><item><item>2</item></item></channel>\
+                     <item>3</item></rss>";
+        assert_eq!(extract("//item", input),
+                   vec!["<item>1</item>", "<item><item>2</item></item>", "<item>3</item>"]);
+        assert_eq!(extract("/rss//item", input).len(), 3);
+        assert_eq!(extract("//channel/item", input).len(), 2);
+        assert_eq!(extract("/rss/item", input), vec!["<item>3</item>"]);
+    }
+
+    #[test]
+    fn test_namespace_context() {
+        let input = "<feed xmlns='urn:a' xmlns:b='urn:b'><entry><b:x/></entry></feed>";
+        let mut p = Parser::new();
+        p.feed_str(input);
+        let mut e = PathExtractor::new();
+        e.define_prefix("a", "urn:a");
+        e.set_pattern("//a:entry").unwrap();
+        let elems: Vec<Element> = p.filter_map(|x| e.handle_event(x)).map(|x| x.unwrap())
+                                   .collect();
+        assert_eq!(elems.len(), 1);
+        assert_eq!(format!("{}", elems[0]), "<entry xmlns='urn:a'><b:x/></entry>");
+        assert!(elems[0].get_child("x", Some("urn:b")).is_some());
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let mut e = PathExtractor::new();
+        assert_eq!(e.set_pattern("item"), Err("item".to_owned()));
+        assert_eq!(e.set_pattern("/x:item"), Err("x:item".to_owned()));
+        assert_eq!(e.set_pattern("/a///b"), Err("/a///b".to_owned()));
+        assert_eq!(e.set_pattern("/a/"), Err("/a/".to_owned()));
+    }
+}
@@ -0,0 +1,450 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use super::{Attributes, Event, StartTag, EndTag};
+use std::collections::VecDeque;
+
+/// Adaptors for iterators over `Event`s, such as a `Parser`
+///
+/// Adaptors keep track of the nesting of elements, so end tags are treated like
+/// their start tags. Elements are matched by their namespace, not their prefix.
+/// Like all iterators over `Event`s the adaptors are `EventSource`s, to be consumed
+/// by an `ElementBuilder` or `XmlWriter`.
+///
+/// ~~~
+/// use xml::{EventFilters, Parser, XmlWriter};
+///
+/// let mut p = Parser::new();
+/// p.feed_str("<doc><!-- draft --><secret/><p>text</p></doc>");
+///
+/// let events = p.strip_comments()
+///               .drop_subtree(|tag| tag.name == "secret")
+///               .rename(|name, ns| if name == "p" {
+///                   Some(("para".to_owned(), ns.map(|x| x.to_owned())))
+///               } else {
+///                   None
+///               });
+/// let mut writer = XmlWriter::new(Vec::new());
+/// for event in events {
+///     writer.write_event(&event.unwrap()).unwrap();
+/// }
+/// assert_eq!(writer.finish().unwrap(), b"<doc><para>text</para></doc>");
+/// ~~~
+pub trait EventFilters<E>: Iterator<Item = Result<Event, E>> + Sized {
+    /// Renames elements. `f` is called with the name and namespace of each element,
+    /// returning `Some((name, ns))` to rename it.
+    /// The prefix is dropped if the namespace changes.
+    fn rename<F>(self, f: F) -> Rename<Self, F>
+        where F: FnMut(&str, Option<&str>) -> Option<(String, Option<String>)>
+    {
+        Rename { iter: self, f, stack: Vec::new() }
+    }
+
+    /// Drops the elements `predicate` returns `true` for, along with their content
+    fn drop_subtree<P>(self, predicate: P) -> DropSubtree<Self, P>
+        where P: FnMut(&StartTag) -> bool
+    {
+        DropSubtree { iter: self, predicate, depth: 0, mappings: 0, queue: VecDeque::new() }
+    }
+
+    /// Modifies the attributes of elements. `f` is called with the name and namespace
+    /// of each element and its attributes.
+    fn map_attributes<F>(self, f: F) -> MapAttributes<Self, F>
+        where F: FnMut(&str, Option<&str>, &mut Attributes)
+    {
+        MapAttributes { iter: self, f }
+    }
+
+    /// Removes comments
+    fn strip_comments(self) -> Strip<Self> {
+        Strip { iter: self, comments: true, pis: false }
+    }
+
+    /// Removes processing instructions
+    fn strip_pis(self) -> Strip<Self> {
+        Strip { iter: self, comments: false, pis: true }
+    }
+
+    /// Inserts events before elements. `f` is called with each start tag,
+    /// returning the events to insert before the element.
+    fn insert_before<F>(self, f: F) -> InsertBefore<Self, F>
+        where F: FnMut(&StartTag) -> Vec<Event>
+    {
+        InsertBefore { iter: self, f, queue: VecDeque::new() }
+    }
+
+    /// Inserts events after elements. `f` is called with each start tag,
+    /// returning the events to insert after the element's end tag
+    /// and the `EndPrefixMapping` events following it.
+    fn insert_after<F>(self, f: F) -> InsertAfter<Self, F>
+        where F: FnMut(&StartTag) -> Vec<Event>
+    {
+        InsertAfter { iter: self, f, stack: Vec::new(), held: Vec::new(), queue: VecDeque::new() }
+    }
+}
+
+impl<I, E> EventFilters<E> for I where I: Iterator<Item = Result<Event, E>> {}
+
+// Returns the next event, along with the `StartPrefixMapping` events preceding it
+fn next_with_mappings<I, E>(iter: &mut I) -> Option<(Vec<Event>, Result<Event, E>)>
+    where I: Iterator<Item = Result<Event, E>>
+{
+    let mut mappings = Vec::new();
+    loop {
+        match iter.next() {
+            Some(Ok(event @ Event::StartPrefixMapping { .. })) => mappings.push(event),
+            Some(event) => return Some((mappings, event)),
+            None => {
+                let last = mappings.pop()?;
+                return Some((mappings, Ok(last)));
+            }
+        }
+    }
+}
+
+/// An iterator renaming elements, as returned by `EventFilters::rename()`
+pub struct Rename<I, F> {
+    iter: I,
+    f: F,
+    // The new names of the open elements, if renamed
+    stack: Vec<Option<EndTag>>
+}
+
+impl<I, F, E> Iterator for Rename<I, F>
+    where I: Iterator<Item = Result<Event, E>>,
+          F: FnMut(&str, Option<&str>) -> Option<(String, Option<String>)>
+{
+    type Item = Result<Event, E>;
+
+    fn next(&mut self) -> Option<Result<Event, E>> {
+        let event = match self.iter.next()? {
+            Ok(Event::ElementStart(mut tag)) => {
+                let renamed = (self.f)(&tag.name, tag.ns.as_deref()).map(|(name, ns)| {
+                    if ns != tag.ns {
+                        tag.prefix = None;
+                    }
+                    tag.name = name;
+                    tag.ns = ns;
+                    EndTag { name: tag.name.clone(), ns: tag.ns.clone(), prefix: tag.prefix.clone() }
+                });
+                self.stack.push(renamed);
+                Event::ElementStart(tag)
+            }
+            Ok(Event::ElementEnd(tag)) => match self.stack.pop() {
+                Some(Some(renamed)) => Event::ElementEnd(renamed),
+                _ => Event::ElementEnd(tag)
+            },
+            event => return Some(event)
+        };
+        Some(Ok(event))
+    }
+}
+
+/// An iterator dropping elements, as returned by `EventFilters::drop_subtree()`
+pub struct DropSubtree<I: Iterator, P> {
+    iter: I,
+    predicate: P,
+    // The depth within the element being dropped
+    depth: usize,
+    // The number of `EndPrefixMapping` events of the dropped element left to drop
+    mappings: usize,
+    queue: VecDeque<I::Item>
+}
+
+impl<I, P, E> Iterator for DropSubtree<I, P>
+    where I: Iterator<Item = Result<Event, E>>,
+          P: FnMut(&StartTag) -> bool
+{
+    type Item = Result<Event, E>;
+
+    fn next(&mut self) -> Option<Result<Event, E>> {
+        loop {
+            if let Some(event) = self.queue.pop_front() {
+                return Some(event);
+            }
+            let (mappings, event) = next_with_mappings(&mut self.iter)?;
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    self.queue.extend(mappings.into_iter().map(Ok));
+                    self.queue.push_back(Err(err));
+                    continue;
+                }
+            };
+
+            if self.depth > 0 {
+                match event {
+                    Event::ElementStart(_) => self.depth += 1,
+                    Event::ElementEnd(_) => self.depth -= 1,
+                    _ => ()
+                }
+                continue;
+            }
+            match event {
+                Event::ElementStart(ref tag) if (self.predicate)(tag) => {
+                    self.depth = 1;
+                    self.mappings = mappings.len();
+                    continue;
+                }
+                Event::EndPrefixMapping { .. } if self.mappings > 0 => {
+                    self.mappings -= 1;
+                    continue;
+                }
+                _ => self.mappings = 0
+            }
+            self.queue.extend(mappings.into_iter().map(Ok));
+            self.queue.push_back(Ok(event));
+        }
+    }
+}
+
+/// An iterator modifying attributes, as returned by `EventFilters::map_attributes()`
+pub struct MapAttributes<I, F> {
+    iter: I,
+    f: F
+}
+
+impl<I, F, E> Iterator for MapAttributes<I, F>
+    where I: Iterator<Item = Result<Event, E>>,
+          F: FnMut(&str, Option<&str>, &mut Attributes)
+{
+    type Item = Result<Event, E>;
+
+    fn next(&mut self) -> Option<Result<Event, E>> {
+        match self.iter.next()? {
+            Ok(Event::ElementStart(mut tag)) => {
+                (self.f)(&tag.name, tag.ns.as_deref(), &mut tag.attributes);
+                Some(Ok(Event::ElementStart(tag)))
+            }
+            event => Some(event)
+        }
+    }
+}
+
+/// An iterator removing comments or processing instructions,
+/// as returned by `EventFilters::strip_comments()` and `EventFilters::strip_pis()`
+pub struct Strip<I> {
+    iter: I,
+    comments: bool,
+    pis: bool
+}
+
+impl<I> Strip<I> {
+    /// Removes comments as well
+    pub fn strip_comments(mut self) -> Strip<I> {
+        self.comments = true;
+        self
+    }
+
+    /// Removes processing instructions as well
+    pub fn strip_pis(mut self) -> Strip<I> {
+        self.pis = true;
+        self
+    }
+}
+
+impl<I, E> Iterator for Strip<I> where I: Iterator<Item = Result<Event, E>> {
+    type Item = Result<Event, E>;
+
+    fn next(&mut self) -> Option<Result<Event, E>> {
+        loop {
+            match self.iter.next()? {
+                Ok(Event::Comment(_)) if self.comments => (),
+                Ok(Event::PI(_)) if self.pis => (),
+                event => return Some(event)
+            }
+        }
+    }
+}
+
+/// An iterator inserting events before elements, as returned by `EventFilters::insert_before()`
+pub struct InsertBefore<I: Iterator, F> {
+    iter: I,
+    f: F,
+    queue: VecDeque<I::Item>
+}
+
+impl<I, F, E> Iterator for InsertBefore<I, F>
+    where I: Iterator<Item = Result<Event, E>>,
+          F: FnMut(&StartTag) -> Vec<Event>
+{
+    type Item = Result<Event, E>;
+
+    fn next(&mut self) -> Option<Result<Event, E>> {
+        if let Some(event) = self.queue.pop_front() {
+            return Some(event);
+        }
+        let (mappings, event) = next_with_mappings(&mut self.iter)?;
+        if let Ok(Event::ElementStart(ref tag)) = event {
+            self.queue.extend((self.f)(tag).into_iter().map(Ok));
+        }
+        self.queue.extend(mappings.into_iter().map(Ok));
+        self.queue.push_back(event);
+        self.queue.pop_front()
+    }
+}
+
+/// An iterator inserting events after elements, as returned by `EventFilters::insert_after()`
+pub struct InsertAfter<I: Iterator, F> {
+    iter: I,
+    f: F,
+    // The events to insert after each open element
+    stack: Vec<Vec<Event>>,
+    // The events to insert after the element just ended, once its EndPrefixMapping events passed
+    held: Vec<Event>,
+    queue: VecDeque<I::Item>
+}
+
+impl<I, F, E> Iterator for InsertAfter<I, F>
+    where I: Iterator<Item = Result<Event, E>>,
+          F: FnMut(&StartTag) -> Vec<Event>
+{
+    type Item = Result<Event, E>;
+
+    fn next(&mut self) -> Option<Result<Event, E>> {
+        if let Some(event) = self.queue.pop_front() {
+            return Some(event);
+        }
+        let event = match self.iter.next() {
+            Some(Ok(event @ Event::EndPrefixMapping { .. })) => return Some(Ok(event)),
+            Some(event) => event,
+            None => {
+                self.queue.extend(self.held.drain(..).map(Ok));
+                return self.queue.pop_front();
+            }
+        };
+        self.queue.extend(self.held.drain(..).map(Ok));
+        match event {
+            Ok(Event::ElementStart(ref tag)) => self.stack.push((self.f)(tag)),
+            Ok(Event::ElementEnd(_)) => self.held = self.stack.pop().unwrap_or_default(),
+            _ => ()
+        }
+        self.queue.push_back(event);
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventFilters;
+    use parser::{Event, Parser, ParserError};
+    use writer::XmlWriter;
+    use {Element, ElementBuilder, EndTag, StartTag};
+
+    fn write<I: Iterator<Item = Result<Event, ParserError>>>(events: I) -> String {
+        let mut writer = XmlWriter::new(Vec::new());
+        for event in events {
+            writer.write_event(&event.unwrap()).unwrap();
+        }
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    fn events(input: &str) -> Vec<Result<Event, ParserError>> {
+        let mut p = Parser::new();
+        p.feed_str(input);
+        p.collect()
+    }
+
+    #[test]
+    fn test_rename() {
+        let input = events("<a xmlns:x='urn:x'><x:b><x:b/></x:b><c/></a>");
+        let output = write(input.into_iter().rename(|name, ns| match (name, ns) {
+            ("b", Some("urn:x")) => Some(("d".to_owned(), Some("urn:x".to_owned()))),
+            ("c", None) => Some(("e".to_owned(), Some("urn:e".to_owned()))),
+            _ => None
+        }));
+        assert_eq!(output, "<a xmlns:x='urn:x'><x:d><x:d/></x:d><e xmlns='urn:e'/></a>");
+    }
+
+    #[test]
+    fn test_drop_subtree() {
+        let input = events("<a><b><b>x</b></b><c><b/>y</c></a>");
+        let output = write(input.into_iter().drop_subtree(|tag| tag.name == "b"));
+        assert_eq!(output, "<a><c>y</c></a>");
+
+        let mut p = Parser::new();
+        p.set_report_prefix_mappings(true);
+        p.feed_str("<a><x:b xmlns:x='urn:x'/><x:c xmlns:x='urn:x'/></a>");
+        let kept: Vec<_> = p.drop_subtree(|tag| tag.name == "b").map(|x| x.unwrap()).collect();
+        assert_eq!(kept.len(), 6);
+        assert_eq!(kept[1], Event::StartPrefixMapping {
+            prefix: Some("x".to_owned()),
+            uri: "urn:x".to_owned()
+        });
+        assert_eq!(kept[4], Event::EndPrefixMapping { prefix: Some("x".to_owned()) });
+    }
+
+    #[test]
+    fn test_map_attributes() {
+        let input = events("<a x='1'><b/></a>");
+        let output = write(input.into_iter().map_attributes(|name, _, attributes| {
+            if name == "b" {
+                attributes.insert(("id".to_owned(), None), "2".to_owned());
+            }
+            attributes.remove_by_name("x", None);
+        }));
+        assert_eq!(output, "<a><b id='2'/></a>");
+    }
+
+    #[test]
+    fn test_strip() {
+        let input = "<?pi?><!--c--><a><?pi?>x<!--c--></a>";
+        assert_eq!(write(events(input).into_iter().strip_comments()), "<?pi?><a><?pi?>x</a>");
+        assert_eq!(write(events(input).into_iter().strip_pis()), "<!--c--><a>x<!--c--></a>");
+        assert_eq!(write(events(input).into_iter().strip_comments().strip_pis()), "<a>x</a>");
+    }
+
+    #[test]
+    fn test_insert() {
+        let start = |name: &str| Event::ElementStart(StartTag {
+            name: name.to_owned(),
+            ns: None,
+            prefix: None,
+            attributes: Default::default()
+        });
+        let end = |name: &str| Event::ElementEnd(EndTag {
+            name: name.to_owned(),
+            ns: None,
+            prefix: None
+        });
+
+        let input = events("<a><b><b/></b></a>");
+        let output = write(input.into_iter()
+            .insert_before(|tag| if tag.name == "b" { vec![Event::Comment("b".to_owned())] }
+                                 else { vec![] })
+            .insert_after(|tag| if tag.name == "b" { vec![start("c"), end("c")] }
+                                else { vec![] }));
+        assert_eq!(output, "<a><!--b--><b><!--b--><b/><c/></b><c/></a>");
+
+        let mut p = Parser::new();
+        p.set_report_prefix_mappings(true);
+        p.feed_str("<a><x:b xmlns:x='urn:x'><x:b/></x:b></a>");
+        let events: Vec<_> = p.insert_after(|tag| vec![Event::Comment(tag.name.clone())])
+                              .map(|x| x.unwrap()).collect();
+        assert_eq!(events.len(), 11);
+        assert_eq!(events[5], Event::Comment("b".to_owned()));
+        assert!(matches!(events[6], Event::ElementEnd(_)));
+        assert_eq!(events[7], Event::EndPrefixMapping { prefix: Some("x".to_owned()) });
+        assert_eq!(events[8], Event::Comment("b".to_owned()));
+        assert!(matches!(events[9], Event::ElementEnd(_)));
+        assert_eq!(events[10], Event::Comment("a".to_owned()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut p = Parser::new();
+        p.feed_str("<a><!--c--><b/><1/>");
+        let mut builder = ElementBuilder::new();
+        let mut events = p.strip_comments().drop_subtree(|tag| tag.name == "b");
+        assert!(matches!(events.next(), Some(Ok(Event::ElementStart(_)))));
+        assert!(matches!(events.next(), Some(Err(_))));
+
+        let elem: Element = "<a><b/>x</a>".parse().unwrap();
+        let mut events = elem.events().map(Ok::<_, ParserError>)
+                             .drop_subtree(|tag| tag.name == "b");
+        assert_eq!(builder.build_from(&mut events), Some(Ok("<a>x</a>".parse().unwrap())));
+    }
+}
@@ -16,24 +16,63 @@
  * An XML parsing library
  */
 
+#[cfg(feature = "tokio")]
+extern crate futures_core;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+
+pub use attributes::{Attributes, AttributeIter};
 pub use parser::Event;
 pub use parser::Parser;
 pub use parser::ParserError;
-pub use element::ChildElements;
+pub use parser::WhitespaceMode;
+pub use parser::{CancelHandle, ParseStatus};
+pub use element::{ChildElements, ElementEvents};
 pub use element::Element;
 pub use element_builder::ElementBuilder;
 pub use element_builder::BuilderError;
+pub use element_builder::Documents;
+pub use element_builder::{StreamBuilder, StreamEvent};
+pub use pull_parser::{PullParser, PullError};
+pub use sax::{ContentHandler, ErrorHandler, Locator};
+pub use checkpoint::Checkpoint;
+pub use extractor::PathExtractor;
+pub use source::{EventSource, SourceError};
+pub use writer::{WriterError, XmlWriter};
+pub use serializer::Serializer;
+pub use filters::{DropSubtree, EventFilters, InsertAfter, InsertBefore, MapAttributes, Rename, Strip};
+#[cfg(feature = "tokio")]
+pub use stream::{ElementStream, EventStream, StreamError};
 
 use std::char;
 use std::fmt;
-use std::collections::HashMap;
+use std::io;
 
+mod attributes;
 mod parser;
 mod element;
 mod element_builder;
+mod pull_parser;
+mod sax;
+mod checkpoint;
+mod extractor;
+mod source;
+mod writer;
+mod filters;
+mod serializer;
+mod html_entities;
+#[cfg(feature = "tokio")]
+mod stream;
 
 // General functions
 
+// Characters an XML 1.1 document can only represent as character references,
+// i.e. control characters and those XML 1.1 treats as line endings.
+fn needs_char_ref(c: char) -> bool {
+    matches!(c, '\u{1}'..='\u{8}' | '\u{B}' | '\u{C}' | '\u{E}'..='\u{1F}'
+              | '\u{7F}'..='\u{9F}' | '\u{2028}')
+}
+
 #[inline]
 /// Escapes ', ", &, <, and > with the appropriate XML entities.
 pub fn escape(input: &str) -> String {
@@ -52,6 +91,27 @@ pub fn escape(input: &str) -> String {
     result
 }
 
+// Resolves a single entity, given without the surrounding '&' and ';'.
+fn unescape_entity(ent: &str) -> Option<char> {
+    match ent {
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "gt"   => Some('>'),
+        "lt"   => Some('<'),
+        "amp"  => Some('&'),
+        ent => {
+            let val = if ent.starts_with("#x") {
+                u32::from_str_radix(&ent[2..], 16).ok()
+            } else if ent.starts_with("#") {
+                u32::from_str_radix(&ent[1..], 10).ok()
+            } else {
+                None
+            };
+            val.and_then(char::from_u32)
+        }
+    }
+}
+
 #[inline]
 /// Unescapes all valid XML entities in a string.
 /// Returns the first invalid entity on failure.
@@ -69,25 +129,9 @@ pub fn unescape(input: &str) -> Result<String, String> {
         match sub.find(';') {
             Some(idx) => {
                 let ent = &sub[..idx];
-                match ent {
-                    "quot" => result.push('"'),
-                    "apos" => result.push('\''),
-                    "gt"   => result.push('>'),
-                    "lt"   => result.push('<'),
-                    "amp"  => result.push('&'),
-                    ent => {
-                        let val = if ent.starts_with("#x") {
-                            u32::from_str_radix(&ent[2..], 16).ok()
-                        } else if ent.starts_with("#") {
-                            u32::from_str_radix(&ent[1..], 10).ok()
-                        } else {
-                            None
-                        };
-                        match val.and_then(char::from_u32) {
-                            Some(c) => result.push(c),
-                            None => return Err(format!("&{};", ent))
-                        }
-                    }
+                match unescape_entity(ent) {
+                    Some(c) => result.push(c),
+                    None => return Err(format!("&{};", ent))
                 }
                 result.push_str(&sub[idx+1..]);
             }
@@ -98,6 +142,24 @@ pub fn unescape(input: &str) -> Result<String, String> {
 }
 
 // General types
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// The version of XML a document conforms to
+pub enum XmlVersion {
+    /// XML 1.0
+    Version10,
+    /// XML 1.1
+    Version11
+}
+
+impl fmt::Display for XmlVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            XmlVersion::Version10 => write!(f, "1.0"),
+            XmlVersion::Version11 => write!(f, "1.1")
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 /// An Enum describing a XML Node
 pub enum Xml {
@@ -110,7 +172,9 @@ pub enum Xml {
     /// A XML Comment
     CommentNode(String),
     /// Processing Information
-    PINode(String)
+    PINode(String),
+    /// A reference to an unknown entity
+    EntityRefNode(String)
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -122,8 +186,8 @@ pub struct StartTag {
     pub ns: Option<String>,
     /// The tag's prefix
     pub prefix: Option<String>,
-    /// The tag's attributes
-    pub attributes: HashMap<(String, Option<String>), String>
+    /// The tag's attributes, in the order they appeared in
+    pub attributes: Attributes
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -137,6 +201,17 @@ pub struct EndTag {
     pub prefix: Option<String>
 }
 
+impl Xml {
+    /// Writes the node to `w`, producing the same output as its `Display` implementation.
+    ///
+    /// Returns the number of bytes written, see `Element::write_to()`.
+    pub fn write_to<W: io::Write + ?Sized>(&self, w: &mut W) -> io::Result<usize> {
+        let mut w = writer::CountingWriter::new(w);
+        element::write_node(self, &mut w)?;
+        Ok(w.count)
+    }
+}
+
 impl fmt::Display for Xml {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -144,7 +219,8 @@ impl fmt::Display for Xml {
             Xml::CharacterNode(ref data) => write!(f, "{}", escape(&data)),
             Xml::CDATANode(ref data) => write!(f, "<![CDATA[{}]]>", &data),
             Xml::CommentNode(ref data) => write!(f, "<!--{}-->", &data),
-            Xml::PINode(ref data) => write!(f, "<?{}?>", &data)
+            Xml::PINode(ref data) => write!(f, "<?{}?>", &data),
+            Xml::EntityRefNode(ref name) => write!(f, "&{};", name)
         }
     }
 }
@@ -159,6 +235,12 @@ mod lib_tests {
         assert_eq!(esc, "&amp;&lt;&gt;&apos;&quot;");
     }
 
+    #[test]
+    fn test_escape_control() {
+        let esc = escape("a\u{1}\tb\u{85}\u{2028}");
+        assert_eq!(esc, "a\u{1}\tb\u{85}\u{2028}");
+    }
+
     #[test]
     fn test_unescape() {
         let unesc = unescape("&amp;lt;&lt;&gt;&apos;&quot;&#x201c;&#x201d;&#38;&#34;");
@@ -206,6 +288,13 @@ mod lib_tests {
         assert_eq!(format!("{}", elem), "<a xmlns='urn:test' href='http://rust-lang.org'/>");
     }
 
+    #[test]
+    fn test_show_element_attribute_order() {
+        let input = "<a z='1' b='2' xmlns:p='urn:p' p:m='3'><b y='4' x='5'/></a>";
+        let elem: Element = input.parse().unwrap();
+        assert_eq!(format!("{}", elem), input);
+    }
+
     #[test]
     fn test_show_characters() {
         let chars = Xml::CharacterNode("some text".to_owned());
@@ -230,6 +319,12 @@ mod lib_tests {
         assert_eq!(format!("{}", chars), "<?xml version='1.0'?>");
     }
 
+    #[test]
+    fn test_show_entity_ref() {
+        let chars = Xml::EntityRefNode("nbsp".to_owned());
+        assert_eq!(format!("{}", chars), "&nbsp;");
+    }
+
     #[test]
     fn test_content_str() {
         let mut elem = Element::new("a".to_owned(), None, vec![]);
@@ -8,12 +8,22 @@
 // ObjFW, Copyright (c) 2008-2013 Jonathan Schleifer.
 // Permission to license this derived work under MIT license has been granted by ObjFW's author.
 
-use super::{unescape, StartTag, EndTag};
+use super::{unescape, unescape_entity, Attributes, StartTag, EndTag, XmlVersion};
+use checkpoint::{Checkpoint, StateReader, StateWriter};
+use element_builder::{Documents, ElementBuilder};
+use sax::{ContentHandler, ErrorHandler, Locator};
+use html_entities::html_entity;
 use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt;
 use std::iter::Iterator;
 use std::mem;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Prefix of the placeholder namespaces unbound prefixes are bound to in lenient mode
+const UNBOUND_NS: &str = "urn:rustyxml:unbound:";
 
 #[derive(PartialEq, Eq, Debug)]
 /// Events returned by the `Parser`
@@ -29,7 +39,83 @@ pub enum Event {
     /// Event indicating CDATA was found
     CDATA(String),
     /// Event indicating a comment was found
-    Comment(String)
+    Comment(String),
+    /// Event indicating a reference to an unknown entity was found,
+    /// only produced when preserving entity references
+    EntityRef(String),
+    /// Event indicating a namespace prefix comes into scope, preceding the `ElementStart`
+    /// of the element declaring it. Only produced when reporting prefix mappings.
+    StartPrefixMapping {
+        /// The declared prefix, `None` for the default namespace
+        prefix: Option<String>,
+        /// The namespace bound to the prefix, empty if the default namespace is unset
+        uri: String
+    },
+    /// Event indicating a namespace prefix goes out of scope, following the `ElementEnd`
+    /// of the element declaring it. Only produced when reporting prefix mappings.
+    EndPrefixMapping {
+        /// The prefix leaving scope, `None` for the default namespace
+        prefix: Option<String>
+    },
+    /// Event indicating character data consisting only of whitespace was found.
+    /// Only produced when whitespace is reported separately, see `WhitespaceMode`.
+    Whitespace(String),
+    /// Event indicating a document starts, preceding its first event.
+    /// Only produced when parsing multiple documents.
+    StartDocument,
+    /// Event indicating a document ended, following the `ElementEnd` of its root element.
+    /// Only produced when parsing multiple documents.
+    EndDocument
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// How the `Parser` handles character data consisting only of whitespace
+pub enum WhitespaceMode {
+    /// Report whitespace as `Event::Characters`, like any other character data
+    Keep,
+    /// Report whitespace as `Event::Whitespace`
+    Report,
+    /// Drop whitespace
+    Drop
+}
+
+#[derive(PartialEq, Debug)]
+/// The result of `Parser::next_with_budget()`
+pub enum ParseStatus {
+    /// An event was parsed, or the error parsing stopped at was found
+    Event(Result<Event, ParserError>),
+    /// The budget was used up before the next event was found, call again to continue
+    Yielded,
+    /// All data fed has been parsed
+    NeedData,
+    /// Parsing was cancelled using a `CancelHandle`
+    Cancelled,
+    /// The root element was closed and `set_stop_at_root_end()` is enabled,
+    /// no further events are produced until the parser is reset
+    Finished,
+    /// Parsing stopped at an error returned before,
+    /// no further events are produced until the parser is reset
+    Failed
+}
+
+#[derive(Clone, Debug)]
+/// A handle to cancel a `Parser`, as returned by `Parser::cancel_handle()`
+///
+/// The handle may be sent to other threads.
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancelHandle {
+    /// Cancels parsing. The parser produces no further events until it is reset.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if parsing was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
 }
 
 
@@ -58,7 +144,7 @@ impl fmt::Display for ParserError {
 }
 
 // Event based parser
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Debug)]
 enum State {
     OutsideTag,
     TagOpened,
@@ -77,9 +163,34 @@ enum State {
     InCommentOpening,
     InComment1,
     InComment2,
-    InDoctype
+    InDoctype,
+    InRawText
 }
 
+// Every state, in the order used to number them in checkpoints.
+// New states are to be appended to keep existing checkpoints valid.
+const STATES: [State; 19] = [
+    State::OutsideTag,
+    State::TagOpened,
+    State::InProcessingInstructions,
+    State::InTagName,
+    State::InCloseTagName,
+    State::InTag,
+    State::InAttrName,
+    State::InAttrValue,
+    State::ExpectDelimiter,
+    State::ExpectClose,
+    State::ExpectSpaceOrClose,
+    State::InExclamationMark,
+    State::InCDATAOpening,
+    State::InCDATA,
+    State::InCommentOpening,
+    State::InComment1,
+    State::InComment2,
+    State::InDoctype,
+    State::InRawText
+];
+
 /// A streaming XML parser
 ///
 /// Data is fed to the parser using the `feed_str()` method.
@@ -91,7 +202,7 @@ enum State {
 ///
 /// let mut p = Parser::new();
 /// p.feed_str("<a href='http://rust-lang.org'>Rust</a>");
-/// for event in p {
+/// for event in &mut p {
 ///     match event {
 ///        // [...]
 ///        _ => ()
@@ -111,16 +222,41 @@ pub struct Parser {
     name: Option<(Option<String>, String)>,
     attr: Option<(Option<String>, String)>,
     delim: Option<char>,
-    level: u8
+    level: u8,
+    open: Vec<(Option<String>, String)>,
+    pending: VecDeque<Event>,
+    lenient: bool,
+    html: bool,
+    namespace_aware: bool,
+    entity_refs: bool,
+    prefix_mappings: bool,
+    strip_ns_decls: bool,
+    coalesce: bool,
+    whitespace: WhitespaceMode,
+    trim: bool,
+    text: Option<String>,
+    version: XmlVersion,
+    after_cr: bool,
+    warnings: Vec<ParserError>,
+    partial_utf8: Vec<u8>,
+    // Whether partial_utf8 holds data which is not valid UTF-8
+    undecodable: bool,
+    stop_at_root_end: bool,
+    root_closed: bool,
+    multiple_documents: bool,
+    in_document: bool,
+    consumed: u64,
+    budget: Option<usize>,
+    yielded: bool,
+    cancelled: Option<Arc<AtomicBool>>,
+    capacity: Option<usize>
 }
 
 impl Parser {
     /// Returns a new `Parser`
     pub fn new() -> Parser {
         let mut ns = HashMap::with_capacity(2);
-        // Add standard namespaces
-        ns.insert("xml".to_owned(), "http://www.w3.org/XML/1998/namespace".to_owned());
-        ns.insert("xmlns".to_owned(), "http://www.w3.org/2000/xmlns/".to_owned());
+        add_standard_namespaces(&mut ns);
 
         Parser {
             line: 1,
@@ -134,29 +270,685 @@ impl Parser {
             name: None,
             attr: None,
             delim: None,
-            level: 0
+            level: 0,
+            open: Vec::new(),
+            pending: VecDeque::new(),
+            lenient: false,
+            html: false,
+            namespace_aware: true,
+            entity_refs: false,
+            prefix_mappings: false,
+            strip_ns_decls: false,
+            coalesce: false,
+            whitespace: WhitespaceMode::Keep,
+            trim: false,
+            text: None,
+            version: XmlVersion::Version10,
+            after_cr: false,
+            warnings: Vec::new(),
+            partial_utf8: Vec::new(),
+            undecodable: false,
+            stop_at_root_end: false,
+            root_closed: false,
+            multiple_documents: false,
+            in_document: false,
+            consumed: 0,
+            budget: None,
+            yielded: false,
+            cancelled: None,
+            capacity: None
+        }
+    }
+
+    /// Enables or disables lenient ("tag soup") parsing.
+    ///
+    /// In lenient mode common mistakes found in real-world documents are repaired
+    /// instead of aborting the parse:
+    ///
+    /// * `&` not starting a valid entity is treated as text
+    /// * attribute values may be unquoted, ending at whitespace or `>`
+    /// * unbound prefixes are bound to the placeholder namespace `urn:rustyxml:unbound:<prefix>`,
+    ///   and a matching `xmlns:<prefix>` declaration is added to the start tag
+    /// * end tags close any elements left open inside them,
+    ///   end tags without a matching start tag are ignored
+    ///
+    /// Every repair is recorded as a warning, see `warnings()`.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Enables or disables HTML mode.
+    ///
+    /// In HTML mode the parser follows the rules of HTML instead of XML:
+    ///
+    /// * tag and attribute names are case-insensitive and reported in lower case,
+    ///   colons and `xmlns` attributes carry no special meaning
+    /// * void elements like `<br>` need no end tag
+    /// * the content of `script` and `style` elements is raw text
+    /// * start tags of e.g. `p`, `li`, `td` and `tr` imply the end of a preceding
+    ///   element that may omit its end tag, and of the elements open inside it,
+    ///   unless an element like `table` or `ul` is open in between
+    /// * attribute values may be unquoted, attributes may be given without value
+    /// * the full set of HTML named character references is recognized,
+    ///   they have to end with `;` however, unlike the legacy ones browsers accept
+    ///
+    /// In addition end tags are handled like in lenient mode.
+    /// No warnings are recorded in HTML mode.
+    ///
+    /// Use it together with an `ElementBuilder` in HTML mode.
+    pub fn set_html(&mut self, html: bool) {
+        self.html = html;
+    }
+
+    /// Enables or disables namespace processing, which is enabled by default.
+    ///
+    /// Without namespace processing prefixes are not resolved, so `ns` is always `None`.
+    /// Tag names are still split into prefix and local part,
+    /// attribute names, including those of `xmlns` attributes, are reported verbatim.
+    /// Documents using undeclared prefixes can be parsed this way.
+    ///
+    /// Use it together with an `ElementBuilder` without namespace processing.
+    pub fn set_namespace_aware(&mut self, namespace_aware: bool) {
+        self.namespace_aware = namespace_aware;
+    }
+
+    /// Enables or disables preserving references to unknown entities.
+    ///
+    /// By default references to entities other than the predefined ones are an error.
+    /// When preserving entity references, such references in character data,
+    /// e.g. `&nbsp;`, are reported as `Event::EntityRef("nbsp")` instead.
+    /// References in attribute values are not affected.
+    pub fn set_preserve_entity_refs(&mut self, entity_refs: bool) {
+        self.entity_refs = entity_refs;
+    }
+
+    /// Enables or disables reporting of namespace prefix mappings.
+    ///
+    /// When enabled, an `Event::StartPrefixMapping` is produced for every namespace
+    /// declared by an element before its `ElementStart`, and an `Event::EndPrefixMapping`
+    /// after its `ElementEnd`. Declarations are reported in document order when starting,
+    /// and ordered by prefix when ending.
+    pub fn set_report_prefix_mappings(&mut self, prefix_mappings: bool) {
+        self.prefix_mappings = prefix_mappings;
+    }
+
+    /// Enables or disables removing namespace declarations from `StartTag::attributes`.
+    ///
+    /// Useful together with reporting prefix mappings, see `set_report_prefix_mappings()`.
+    pub fn set_strip_namespace_declarations(&mut self, strip: bool) {
+        self.strip_ns_decls = strip;
+    }
+
+    /// Enables or disables coalescing of character data.
+    ///
+    /// When enabled, adjacent character data and CDATA sections are reported as a single
+    /// `Event::Characters`. Note that this event is only produced once the event following
+    /// it has been parsed.
+    pub fn set_coalesce_text(&mut self, coalesce: bool) {
+        self.coalesce = coalesce;
+    }
+
+    /// Sets how character data consisting only of whitespace is handled.
+    /// The default is `WhitespaceMode::Keep`.
+    pub fn set_whitespace_mode(&mut self, mode: WhitespaceMode) {
+        self.whitespace = mode;
+    }
+
+    /// Enables or disables trimming of character data.
+    ///
+    /// When enabled, leading and trailing whitespace is removed from `Event::Characters`,
+    /// events left empty are dropped.
+    pub fn set_trim_text(&mut self, trim: bool) {
+        self.trim = trim;
+    }
+
+    /// Enables or disables stopping after the root element's end tag.
+    ///
+    /// When enabled no events are returned after the root element has been closed,
+    /// and the data following its end tag can be retrieved with `take_remaining()`
+    /// or `take_remaining_bytes()`, e.g. to hand it to another protocol layer.
+    pub fn set_stop_at_root_end(&mut self, stop: bool) {
+        self.stop_at_root_end = stop;
+    }
+
+    /// Enables or disables parsing a sequence of documents.
+    ///
+    /// Each document is enclosed in `StartDocument` and `EndDocument` events, and ends
+    /// with its root element. Namespace declarations, the XML version and other
+    /// document-level state are reset between documents.
+    /// Whitespace between documents is dropped, while comments and processing instructions
+    /// following a root element are part of the next document.
+    pub fn set_multiple_documents(&mut self, multiple: bool) {
+        self.multiple_documents = multiple;
+    }
+
+    /// Returns the XML version of the document being parsed.
+    ///
+    /// The version is taken from the XML declaration and defaults to 1.0.
+    /// XML 1.1 documents may contain references to control characters, and use
+    /// NEL (U+0085) and LSEP (U+2028) as line endings, which are normalized to `\n`.
+    /// Both versions use the same rules for names.
+    pub fn version(&self) -> XmlVersion {
+        self.version
+    }
+
+    /// Returns the warnings recorded for problems repaired in lenient mode
+    pub fn warnings(&self) -> &[ParserError] {
+        &self.warnings
+    }
+
+    /// Returns an iterator over the root elements of the documents parsed,
+    /// built using an `ElementBuilder` configured to match this parser
+    pub fn documents(&mut self) -> Documents<'_> {
+        let builder = self.element_builder();
+        Documents::new(self, builder)
+    }
+
+    // Returns an ElementBuilder configured to match this parser
+    pub(crate) fn element_builder(&self) -> ElementBuilder {
+        let mut builder = ElementBuilder::new();
+        builder.set_lenient(self.lenient);
+        builder.set_html(self.html);
+        builder.set_namespace_aware(self.namespace_aware);
+        builder
+    }
+
+    /// Sets the capacity of the input buffer, in characters, `None` for no limit.
+    ///
+    /// Data exceeding the capacity is not accepted by `try_feed_str()` and `feed_bytes()`.
+    /// As every byte of input results in at most one character, reading up to
+    /// `free_capacity()` bytes from a socket never exceeds the capacity.
+    pub fn set_input_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+    }
+
+    /// Returns the number of characters which can be fed before the input buffer is full.
+    /// Bytes fed with `feed_bytes()` but not decoded yet take up capacity as well,
+    /// the start of a multi-byte sequence counting as the character it encodes.
+    pub fn free_capacity(&self) -> usize {
+        let undecoded = if self.undecodable {
+            self.partial_utf8.len()
+        } else {
+            self.partial_utf8.len().min(1)
+        };
+        match self.capacity {
+            Some(capacity) => capacity.saturating_sub(self.data.len() + undecoded),
+            None => usize::MAX
         }
     }
 
-    /// Feeds a string slice to the parser
+    /// Feeds a string slice to the parser, regardless of the input buffer's capacity
     pub fn feed_str(&mut self, data: &str) {
         self.data.extend(data.chars());
     }
+
+    /// Feeds as much of a string slice to the parser as fits into the input buffer.
+    ///
+    /// Returns the number of bytes accepted.
+    pub fn try_feed_str(&mut self, data: &str) -> usize {
+        let accepted = prefix_len(data, self.free_capacity());
+        self.data.extend(data[..accepted].chars());
+        accepted
+    }
+
+    /// Feeds UTF-8 encoded bytes to the parser, as much as fits into the input buffer.
+    ///
+    /// Returns the number of bytes accepted. A multi-byte sequence may be split across calls,
+    /// its start is accepted and kept until the rest is fed.
+    /// If the data is not valid UTF-8 an error is returned, and the data following
+    /// the last valid character is discarded.
+    ///
+    /// If `set_stop_at_root_end()` is enabled, invalid data is accepted instead and kept
+    /// undecoded, as it may follow the root element, e.g. a TLS handshake. It is returned
+    /// by `take_remaining_bytes()` as fed, and counts against the input buffer's capacity.
+    /// Parsing fails with an error if it is reached before the root element is closed.
+    pub fn feed_bytes(&mut self, data: &[u8]) -> Result<usize, ParserError> {
+        if self.undecodable {
+            let accepted = data.len().min(self.free_capacity());
+            self.partial_utf8.extend_from_slice(&data[..accepted]);
+            return Ok(accepted);
+        }
+        let held = self.partial_utf8.len();
+        let mut bytes = mem::take(&mut self.partial_utf8);
+        bytes.extend_from_slice(data);
+        let (valid, invalid) = match str::from_utf8(&bytes) {
+            Ok(s) => (s, false),
+            Err(e) => (str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(), e.error_len().is_some())
+        };
+
+        let accepted = prefix_len(valid, self.free_capacity());
+        self.data.extend(valid[..accepted].chars());
+        if accepted < valid.len() {
+            if accepted == 0 {
+                // Keep the incomplete sequence fed before
+                bytes.truncate(held);
+                self.partial_utf8 = bytes;
+                return Ok(0);
+            }
+            return Ok(accepted - held);
+        }
+        if invalid {
+            if !self.stop_at_root_end {
+                return Err(ParserError { line: self.line, col: self.col, msg: "Invalid UTF-8" });
+            }
+            self.undecodable = true;
+            // Bytes held from before were accepted already
+            let kept = (bytes.len() - valid.len()).min(self.free_capacity())
+                                                  .max(held.saturating_sub(valid.len()));
+            self.partial_utf8.extend_from_slice(&bytes[valid.len()..valid.len() + kept]);
+            return Ok(valid.len() + kept - held);
+        }
+        self.partial_utf8.extend_from_slice(&bytes[valid.len()..]);
+        Ok(data.len())
+    }
+
+    /// Returns the data fed but not yet parsed, removing it from the parser.
+    ///
+    /// An incomplete UTF-8 sequence or undecoded data fed with `feed_bytes()` is dropped,
+    /// use `take_remaining_bytes()` to retrieve it.
+    pub fn take_remaining(&mut self) -> String {
+        self.partial_utf8.clear();
+        self.undecodable = false;
+        self.data.drain(..).collect()
+    }
+
+    /// Returns the data fed but not yet parsed as UTF-8 encoded bytes, removing it from the parser
+    pub fn take_remaining_bytes(&mut self) -> Vec<u8> {
+        let mut bytes = self.data.drain(..).collect::<String>().into_bytes();
+        bytes.append(&mut self.partial_utf8);
+        self.undecodable = false;
+        bytes
+    }
+
+    /// Checks that the input was complete, once no more data will be fed
+    /// and all events have been read.
+    ///
+    /// Returns an error if elements are still open, or if a tag or other markup,
+    /// or a UTF-8 sequence fed with `feed_bytes()`, is incomplete. Data following the
+    /// root element is not checked if `set_stop_at_root_end()` is enabled.
+    pub fn finish(&self) -> Result<(), ParserError> {
+        if self.stopped() {
+            return Ok(());
+        }
+        if !self.open.is_empty() || !matches!(self.st, State::OutsideTag)
+           || (!self.partial_utf8.is_empty() && !self.undecodable) {
+            return Err(ParserError { line: self.line, col: self.col,
+                                     msg: "Unexpected end of input" });
+        }
+        Ok(())
+    }
+
+    // Whether no further events are produced until the parser is reset,
+    // after an error or the end of the root element
+    pub(crate) fn stopped(&self) -> bool {
+        self.has_error || (self.stop_at_root_end && self.root_closed)
+    }
+
+    /// Resets the parser to parse a new document, as required e.g. to restart an XMPP stream.
+    ///
+    /// All parsing state, including open elements, namespace declarations, errors
+    /// and the position, is cleared, while configuration and allocations are kept.
+    /// Offsets reported by `checkpoint()` count from the reset on.
+    /// Data fed but not yet parsed is kept if `keep_input` is true, otherwise it is discarded.
+    pub fn reset(&mut self, keep_input: bool) {
+        self.line = 1;
+        self.col = 0;
+        self.consumed = 0;
+        self.has_error = false;
+        if !keep_input {
+            self.data.clear();
+            self.partial_utf8.clear();
+            self.undecodable = false;
+        }
+        self.buf.clear();
+        self.reset_namespaces();
+        self.attributes.clear();
+        self.st = State::OutsideTag;
+        self.name = None;
+        self.attr = None;
+        self.delim = None;
+        self.level = 0;
+        self.open.clear();
+        self.pending.clear();
+        self.text = None;
+        self.version = XmlVersion::Version10;
+        self.after_cr = false;
+        self.warnings.clear();
+        self.root_closed = false;
+        self.in_document = false;
+        if let Some(ref cancelled) = self.cancelled {
+            cancelled.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the next event, parsing at most `max_chars` characters of input.
+    ///
+    /// If the budget is used up before an event is complete `ParseStatus::Yielded` is returned,
+    /// and parsing continues where it stopped on the next call. The events produced are
+    /// the same as those returned by the iterator.
+    pub fn next_with_budget(&mut self, max_chars: usize) -> ParseStatus {
+        self.budget = Some(max_chars);
+        self.yielded = false;
+        let event = (&mut *self).next();
+        self.budget = None;
+        match event {
+            Some(event) => ParseStatus::Event(event),
+            None if self.yielded => ParseStatus::Yielded,
+            None if self.has_error => ParseStatus::Failed,
+            None if self.is_cancelled() => ParseStatus::Cancelled,
+            None if self.stop_at_root_end && self.root_closed => ParseStatus::Finished,
+            None => ParseStatus::NeedData
+        }
+    }
+
+    /// Returns a handle which can be used to cancel parsing, e.g. from another thread.
+    ///
+    /// Once cancelled, no further events are produced until the parser is reset.
+    pub fn cancel_handle(&mut self) -> CancelHandle {
+        let cancelled = self.cancelled.get_or_insert_with(|| Arc::new(AtomicBool::new(false)));
+        CancelHandle { cancelled: cancelled.clone() }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.as_ref().is_some_and(|x| x.load(Ordering::Relaxed))
+    }
+
+    /// Returns a snapshot of the parser's state, from which parsing can be resumed
+    /// using `from_checkpoint()`.
+    ///
+    /// Returns `None` if events are still queued for the last markup parsed,
+    /// a checkpoint can then be taken after the next event. `None` is returned as well
+    /// once data which is not valid UTF-8 was fed with `feed_bytes()`.
+    /// Warnings and data fed but not yet parsed are not part of the checkpoint.
+    pub fn checkpoint(&self) -> Option<Checkpoint> {
+        if !self.pending.is_empty() || self.undecodable {
+            return None;
+        }
+
+        let mut w = StateWriter::new();
+        for &flag in &[self.lenient, self.html, self.namespace_aware, self.entity_refs,
+                       self.prefix_mappings, self.strip_ns_decls, self.coalesce, self.trim,
+                       self.stop_at_root_end, self.multiple_documents] {
+            w.flag(flag);
+        }
+        w.num(match self.whitespace {
+            WhitespaceMode::Keep => 0,
+            WhitespaceMode::Report => 1,
+            WhitespaceMode::Drop => 2
+        });
+
+        w.num(u64::from(self.line));
+        w.num(u64::from(self.col));
+        w.flag(self.has_error);
+        w.string(&self.buf);
+        w.num(self.namespaces.len() as u64);
+        for scope in &self.namespaces {
+            let mut decls: Vec<_> = scope.iter().collect();
+            decls.sort();
+            w.num(decls.len() as u64);
+            for (prefix, ns) in decls {
+                w.string(prefix);
+                w.string(ns);
+            }
+        }
+        w.num(self.attributes.len() as u64);
+        for (name, prefix, value) in &self.attributes {
+            w.string(name);
+            w.opt_string(prefix.as_ref().map(|x| &x[..]));
+            w.string(value);
+        }
+        w.num(STATES.iter().position(|x| *x == self.st).unwrap() as u64);
+        for name in &[&self.name, &self.attr] {
+            w.flag(name.is_some());
+            if let Some((ref prefix, ref name)) = **name {
+                w.opt_string(prefix.as_ref().map(|x| &x[..]));
+                w.string(name);
+            }
+        }
+        w.opt_string(self.delim.map(String::from).as_ref().map(|x| &x[..]));
+        w.num(u64::from(self.level));
+        w.num(self.open.len() as u64);
+        for (prefix, name) in &self.open {
+            w.opt_string(prefix.as_ref().map(|x| &x[..]));
+            w.string(name);
+        }
+        w.opt_string(self.text.as_ref().map(|x| &x[..]));
+        w.flag(self.version == XmlVersion::Version11);
+        w.flag(self.after_cr);
+        w.flag(self.root_closed);
+        w.flag(self.in_document);
+
+        Some(Checkpoint::new(self.consumed, w.finish()))
+    }
+
+    /// Returns a parser in the state saved in `checkpoint`.
+    ///
+    /// The input following the checkpoint's offset is to be fed to the parser,
+    /// which then produces the events the parser the checkpoint was taken from would have.
+    pub fn from_checkpoint(checkpoint: &Checkpoint) -> Result<Parser, ParserError> {
+        let mut p = Parser::new();
+        match p.restore(checkpoint.state()) {
+            Some(()) => {
+                p.consumed = checkpoint.offset();
+                Ok(p)
+            }
+            None => Err(ParserError { line: 0, col: 0, msg: "Invalid checkpoint" })
+        }
+    }
+
+    // Restore the state written by checkpoint()
+    fn restore(&mut self, state: &str) -> Option<()> {
+        let mut r = StateReader::new(state);
+        self.lenient = r.flag()?;
+        self.html = r.flag()?;
+        self.namespace_aware = r.flag()?;
+        self.entity_refs = r.flag()?;
+        self.prefix_mappings = r.flag()?;
+        self.strip_ns_decls = r.flag()?;
+        self.coalesce = r.flag()?;
+        self.trim = r.flag()?;
+        self.stop_at_root_end = r.flag()?;
+        self.multiple_documents = r.flag()?;
+        self.whitespace = match r.num()? {
+            0 => WhitespaceMode::Keep,
+            1 => WhitespaceMode::Report,
+            2 => WhitespaceMode::Drop,
+            _ => return None
+        };
+
+        self.line = r.num()? as u32;
+        self.col = r.num()? as u32;
+        self.has_error = r.flag()?;
+        self.buf = r.string()?;
+        self.namespaces.clear();
+        for _ in 0..r.num()? {
+            let mut scope = HashMap::new();
+            for _ in 0..r.num()? {
+                scope.insert(r.string()?, r.string()?);
+            }
+            self.namespaces.push(scope);
+        }
+        for _ in 0..r.num()? {
+            self.attributes.push((r.string()?, r.opt_string()?, r.string()?));
+        }
+        self.st = STATES.get(r.num()? as usize)?.clone();
+        self.name = if r.flag()? { Some((r.opt_string()?, r.string()?)) } else { None };
+        self.attr = if r.flag()? { Some((r.opt_string()?, r.string()?)) } else { None };
+        self.delim = match r.opt_string()? {
+            Some(delim) => Some(delim.chars().next()?),
+            None => None
+        };
+        self.level = r.num()? as u8;
+        for _ in 0..r.num()? {
+            self.open.push((r.opt_string()?, r.string()?));
+        }
+        self.text = r.opt_string()?;
+        self.version = if r.flag()? { XmlVersion::Version11 } else { XmlVersion::Version10 };
+        self.after_cr = r.flag()?;
+        self.root_closed = r.flag()?;
+        self.in_document = r.flag()?;
+
+        if r.is_empty() && !self.namespaces.is_empty() { Some(()) } else { None }
+    }
+
+    /// Returns the position of the last character consumed
+    pub fn locator(&self) -> Locator {
+        Locator { line: self.line, col: self.col }
+    }
+
+    /// Parses the data fed so far, calling `handler` for every event.
+    ///
+    /// Problems repaired in lenient mode are reported as warnings. More data may be fed
+    /// before calling this again, once the input is complete `finish_with()` is to be called
+    /// instead. An error stopping the parse is reported as fatal and returned.
+    pub fn parse_with<H>(&mut self, handler: &mut H) -> Result<(), ParserError>
+        where H: ContentHandler + ErrorHandler
+    {
+        let mut warnings = self.warnings.len();
+        loop {
+            let event = (&mut *self).next();
+            for warning in &self.warnings[warnings..] {
+                handler.warning(warning);
+            }
+            warnings = self.warnings.len();
+
+            let event = match event {
+                Some(Ok(event)) => event,
+                Some(Err(err)) => {
+                    handler.fatal_error(&err);
+                    return Err(err);
+                }
+                None => break
+            };
+            handler.set_document_locator(self.locator());
+            match event {
+                Event::PI(ref data) => {
+                    let is_space = |c| matches!(c, ' ' | '\t' | '\r' | '\n');
+                    let mut parts = data.splitn(2, is_space);
+                    let target = parts.next().unwrap_or("");
+                    let data = parts.next().unwrap_or("").trim_start_matches(is_space);
+                    handler.processing_instruction(target, data);
+                }
+                Event::ElementStart(ref tag) => handler.start_element(tag),
+                Event::ElementEnd(ref tag) => handler.end_element(tag),
+                Event::Characters(ref text) => handler.characters(text),
+                Event::CDATA(ref text) => handler.cdata(text),
+                Event::Comment(ref text) => handler.comment(text),
+                Event::EntityRef(ref name) => handler.skipped_entity(name),
+                Event::StartPrefixMapping { ref prefix, ref uri } => {
+                    handler.start_prefix_mapping(prefix.as_ref().map(|x| &x[..]), uri)
+                }
+                Event::EndPrefixMapping { ref prefix } => {
+                    handler.end_prefix_mapping(prefix.as_ref().map(|x| &x[..]))
+                }
+                Event::Whitespace(ref text) => handler.ignorable_whitespace(text),
+                Event::StartDocument => handler.start_document(),
+                Event::EndDocument => handler.end_document()
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the data fed so far like `parse_with()`, once no more data will be fed.
+    ///
+    /// If the input ends while elements are still open, or within a tag or other markup,
+    /// an error is reported, see `finish()`.
+    pub fn finish_with<H>(&mut self, handler: &mut H) -> Result<(), ParserError>
+        where H: ContentHandler + ErrorHandler
+    {
+        self.parse_with(handler)?;
+        if let Err(err) = self.finish() {
+            handler.error(&err);
+        }
+        Ok(())
+    }
 }
 
 impl<'a> Iterator for &'a mut Parser {
     type Item = Result<Event, ParserError>;
 
     fn next(&mut self) -> Option<Result<Event, ParserError>> {
+        if self.coalesce || self.trim || self.whitespace != WhitespaceMode::Keep {
+            self.next_shaped_event()
+        } else {
+            self.next_event()
+        }
+    }
+}
+
+impl Parser {
+    // Get the next event, delimiting documents if requested
+    fn next_event(&mut self) -> Option<Result<Event, ParserError>> {
+        if !self.multiple_documents {
+            return self.next_parsed_event();
+        }
+        loop {
+            let event = match self.next_parsed_event() {
+                Some(Ok(event)) => event,
+                other => return other
+            };
+            if self.in_document {
+                if event == Event::EndDocument {
+                    self.in_document = false;
+                }
+                return Some(Ok(event));
+            }
+            if let Event::Characters(ref text) = event {
+                if text.chars().all(|c| matches!(c, ' ' | '\t' | '\r' | '\n')) {
+                    continue;
+                }
+            }
+            self.in_document = true;
+            self.pending.push_front(event);
+            return Some(Ok(Event::StartDocument));
+        }
+    }
+
+    // Get the next event as parsed
+    fn next_parsed_event(&mut self) -> Option<Result<Event, ParserError>> {
         if self.has_error {
             return None;
         }
 
         loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+
+            if self.stop_at_root_end && self.root_closed {
+                return None;
+            }
+
+            if self.is_cancelled() {
+                return None;
+            }
+
+            if let Some(ref mut budget) = self.budget {
+                if *budget == 0 {
+                    self.yielded = !self.data.is_empty();
+                    return None;
+                }
+                *budget -= 1;
+            }
+
             let c = match self.data.pop_front() {
                 Some(c) => c,
+                None if self.undecodable => {
+                    self.has_error = true;
+                    return Some(Err(ParserError {
+                        line: self.line,
+                        col: self.col,
+                        msg: "Invalid UTF-8"
+                    }));
+                }
                 None => return None
             };
+            self.consumed += c.len_utf8() as u64;
+
+            let c = match self.normalize_line_ending(c) {
+                Some(c) => c,
+                None => continue
+            };
 
             if c == '\n' {
                 self.line += 1;
@@ -165,7 +957,16 @@ impl<'a> Iterator for &'a mut Parser {
                 self.col += 1;
             }
 
-            match self.parse_character(c) {
+            let result = if self.html || self.is_valid_char(c) {
+                self.parse_character(c)
+            } else if self.lenient {
+                self.warn("Invalid character");
+                Ok(None)
+            } else {
+                self.error("Invalid character")
+            };
+
+            match result {
                 Ok(None) => continue,
                 Ok(Some(event)) => {
                     return Some(Ok(event));
@@ -177,6 +978,54 @@ impl<'a> Iterator for &'a mut Parser {
             }
         }
     }
+
+    // Get the next event, with character data shaped as requested
+    fn next_shaped_event(&mut self) -> Option<Result<Event, ParserError>> {
+        loop {
+            let event = match self.next_event() {
+                Some(Ok(event)) => event,
+                // Keep coalesced text until it is known to be complete
+                other => return other
+            };
+            let text = match event {
+                Event::Characters(text) => text,
+                Event::CDATA(text) if self.coalesce => text,
+                event => {
+                    match self.text.take().and_then(|text| self.shape_text(text)) {
+                        Some(text) => {
+                            self.pending.push_front(event);
+                            return Some(Ok(text));
+                        }
+                        None => return Some(Ok(event))
+                    }
+                }
+            };
+            if self.coalesce {
+                self.text.get_or_insert_with(String::new).push_str(&text);
+            } else if let Some(event) = self.shape_text(text) {
+                return Some(Ok(event));
+            }
+        }
+    }
+
+    // Produce the event for a run of character data, None if it is to be dropped
+    fn shape_text(&self, text: String) -> Option<Event> {
+        let is_space = |c| matches!(c, ' ' | '\t' | '\r' | '\n');
+        if text.chars().all(is_space) {
+            match self.whitespace {
+                WhitespaceMode::Report if !text.is_empty() => return Some(Event::Whitespace(text)),
+                WhitespaceMode::Keep if !self.trim && !text.is_empty() => (),
+                _ => return None
+            }
+        }
+        if self.trim {
+            let trimmed = text.trim_matches(is_space);
+            if trimmed.len() != text.len() {
+                return Some(Event::Characters(trimmed.to_owned()));
+            }
+        }
+        Some(Event::Characters(text))
+    }
 }
 
 #[inline]
@@ -189,6 +1038,131 @@ fn parse_qname(qname: &str) -> (Option<String>, String) {
     }
 }
 
+// Unescapes like `unescape()`, but keeps anything that is not a valid entity as literal text.
+// Optionally HTML named character references are recognized as well.
+fn unescape_lossy(input: &str, html: bool) -> String {
+    let mut result = String::with_capacity(input.len());
+
+    let mut it = input.split('&');
+    if let Some(sub) = it.next() {
+        result.push_str(sub);
+    }
+
+    for sub in it {
+        let idx = match sub.find(';') {
+            Some(idx) => idx,
+            None => {
+                result.push('&');
+                result.push_str(sub);
+                continue;
+            }
+        };
+        let ent = &sub[..idx];
+        if let Some(c) = unescape_entity(ent) {
+            result.push(c);
+        } else if let Some(s) = html_entity(ent).filter(|_| html) {
+            result.push_str(s);
+        } else {
+            result.push('&');
+            result.push_str(ent);
+            result.push(';');
+        }
+        result.push_str(&sub[idx+1..]);
+    }
+    result
+}
+
+fn is_name_start_char(c: char) -> bool {
+    matches!(c, ':' | 'A'..='Z' | '_' | 'a'..='z' | '\u{C0}'..='\u{D6}' | '\u{D8}'..='\u{F6}'
+              | '\u{F8}'..='\u{2FF}' | '\u{370}'..='\u{37D}' | '\u{37F}'..='\u{1FFF}'
+              | '\u{200C}'..='\u{200D}' | '\u{2070}'..='\u{218F}' | '\u{2C00}'..='\u{2FEF}'
+              | '\u{3001}'..='\u{D7FF}' | '\u{F900}'..='\u{FDCF}' | '\u{FDF0}'..='\u{FFFD}'
+              | '\u{10000}'..='\u{EFFFF}')
+}
+
+fn is_name_char(c: char) -> bool {
+    is_name_start_char(c)
+        || matches!(c, '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}'
+                     | '\u{203F}'..='\u{2040}')
+}
+
+// Whether a string matches the Name production,
+// which is the same for XML 1.1 and XML 1.0 since its fifth edition
+pub(crate) fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_name_start_char) && chars.all(is_name_char)
+}
+
+// Get the version from the content of an XML declaration
+fn xml_decl_version(decl: &str) -> Option<XmlVersion> {
+    let rest = decl.trim_start().strip_prefix("version")?.trim_start().strip_prefix('=')?;
+    let rest = rest.trim_start();
+    let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let version = rest[1..].split(quote).next()?;
+    match version {
+        "1.1" => Some(XmlVersion::Version11),
+        // Other 1.x versions are to be processed as 1.0
+        v if v.starts_with("1.") => Some(XmlVersion::Version10),
+        _ => None
+    }
+}
+
+// Binds the xml and xmlns prefixes
+fn add_standard_namespaces(ns: &mut HashMap<String, String>) {
+    ns.insert("xml".to_owned(), "http://www.w3.org/XML/1998/namespace".to_owned());
+    ns.insert("xmlns".to_owned(), "http://www.w3.org/2000/xmlns/".to_owned());
+}
+
+// Returns the length in bytes of the first max_chars characters of s
+fn prefix_len(s: &str, max_chars: usize) -> usize {
+    match s.char_indices().nth(max_chars) {
+        Some((idx, _)) => idx,
+        None => s.len()
+    }
+}
+
+// Elements without content, which have no end tag in HTML
+fn is_void_element(name: &str) -> bool {
+    matches!(name, "area" | "base" | "br" | "col" | "embed" | "hr" | "img" | "input"
+                 | "link" | "meta" | "param" | "source" | "track" | "wbr")
+}
+
+// Elements whose content is raw text in HTML
+fn is_raw_text_element(name: &str) -> bool {
+    matches!(name, "script" | "style")
+}
+
+// Whether, in HTML, a start tag of element `start` implies the end of the open element `open`
+fn implies_end(open: &str, start: &str) -> bool {
+    match open {
+        "p" => matches!(start, "address" | "article" | "aside" | "blockquote" | "dd" | "details"
+                             | "div" | "dl" | "dt" | "fieldset" | "figcaption" | "figure"
+                             | "footer" | "form" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6"
+                             | "header" | "hr" | "li" | "main" | "nav" | "ol" | "p" | "pre"
+                             | "section" | "table" | "ul"),
+        "li" => start == "li",
+        "dt" | "dd" => matches!(start, "dt" | "dd"),
+        "td" | "th" => matches!(start, "td" | "th" | "tr" | "thead" | "tbody" | "tfoot"),
+        "tr" => matches!(start, "tr" | "thead" | "tbody" | "tfoot"),
+        "thead" | "tbody" => matches!(start, "tbody" | "tfoot"),
+        "option" => matches!(start, "option" | "optgroup"),
+        "optgroup" => start == "optgroup",
+        _ => false
+    }
+}
+
+// Whether, in HTML, the open element `open` bounds the search for an element
+// whose end a start tag of element `start` implies, like the scopes of HTML do
+fn is_scope_boundary(open: &str, start: &str) -> bool {
+    matches!(open, "applet" | "button" | "caption" | "html" | "marquee" | "object" | "select"
+                 | "table" | "td" | "template" | "th")
+        || match start {
+            "li" => matches!(open, "ol" | "ul"),
+            "dt" | "dd" => open == "dl",
+            _ => false
+        }
+}
+
 impl Parser {
     // Get the namespace currently bound to a prefix.
     // Bindings are stored as a stack of HashMaps, we start searching in the top most HashMap
@@ -205,8 +1179,214 @@ impl Parser {
         None
     }
 
+    // Resolve a prefix to a namespace, failing with msg if it is unbound.
+    // In lenient mode unbound prefixes are bound to a placeholder namespace instead.
+    fn resolve_prefix(&mut self, prefix: Option<&str>,
+                      msg: &'static str) -> Result<Option<String>, ParserError> {
+        if !self.namespace_aware {
+            return Ok(None);
+        }
+        let pre = match prefix {
+            None => return Ok(self.namespace_for_prefix("")),
+            Some(pre) => pre
+        };
+        match self.namespace_for_prefix(pre) {
+            None if self.lenient => {
+                self.warn(msg);
+                let ns = format!("{}{}", UNBOUND_NS, pre);
+                if let Some(last) = self.namespaces.last_mut() {
+                    last.insert(pre.to_owned(), ns.clone());
+                }
+                Ok(Some(ns))
+            }
+            None => Err(ParserError { line: self.line, col: self.col, msg }),
+            ns => Ok(ns)
+        }
+    }
+
+    // Normalize line endings to '\n', returning None for characters to drop.
+    fn normalize_line_ending(&mut self, c: char) -> Option<char> {
+        let after_cr = mem::replace(&mut self.after_cr, false);
+        let xml11 = self.version == XmlVersion::Version11;
+        match c {
+            '\r' => {
+                self.after_cr = true;
+                Some('\n')
+            }
+            '\n' if after_cr => None,
+            '\u{85}' if xml11 && after_cr => None,
+            '\u{85}' | '\u{2028}' if xml11 => Some('\n'),
+            c => Some(c)
+        }
+    }
+
+    // Whether a character may appear literally in the document
+    fn is_valid_char(&self, c: char) -> bool {
+        match c {
+            '\t' | '\n' | '\r' => true,
+            '\u{0}'..='\u{1F}' | '\u{FFFE}' | '\u{FFFF}' => false,
+            // Restricted characters of XML 1.1
+            '\u{7F}'..='\u{84}' | '\u{86}'..='\u{9F}' => self.version == XmlVersion::Version10,
+            _ => true
+        }
+    }
+
+    // Whether a character may be referenced using a character reference
+    fn is_valid_char_ref(&self, c: char) -> bool {
+        match c {
+            '\u{0}' | '\u{FFFE}' | '\u{FFFF}' => false,
+            '\u{1}'..='\u{1F}' => self.version == XmlVersion::Version11 || self.is_valid_char(c),
+            _ => true
+        }
+    }
+
+    // Check unescaped text for references to characters not allowed in the document.
+    // In lenient mode they are dropped instead.
+    fn check_char_refs(&mut self, text: String) -> Result<String, ParserError> {
+        if self.html || text.chars().all(|c| self.is_valid_char_ref(c)) {
+            return Ok(text);
+        }
+        if self.lenient {
+            self.warn("Invalid character reference");
+            return Ok(text.chars().filter(|&c| self.is_valid_char_ref(c)).collect());
+        }
+        Err(ParserError { line: self.line, col: self.col, msg: "Invalid character reference" })
+    }
+
+    // Check that buf holds a valid name. In lenient mode invalid names are accepted.
+    fn check_name(&mut self) -> Result<(), ParserError> {
+        if self.html || is_name(&self.buf) {
+            Ok(())
+        } else if self.lenient {
+            self.warn("Invalid name");
+            Ok(())
+        } else {
+            Err(ParserError { line: self.line, col: self.col, msg: "Invalid name" })
+        }
+    }
+
+    // Parse a tag or attribute name. In HTML mode names are case-insensitive
+    // and never have a prefix.
+    fn parse_name(&self, name: &str) -> (Option<String>, String) {
+        if self.html {
+            (None, name.to_lowercase())
+        } else {
+            parse_qname(name)
+        }
+    }
+
+    // Parse an attribute name, which is taken verbatim without namespace processing
+    fn parse_attr_name(&self, name: &str) -> (Option<String>, String) {
+        if self.namespace_aware {
+            self.parse_name(name)
+        } else {
+            (None, name.to_owned())
+        }
+    }
+
+    fn unescape_buf(&mut self) -> Result<String, ParserError> {
+        if self.html {
+            return Ok(unescape_lossy(&self.buf, true));
+        }
+        match unescape(&self.buf) {
+            Ok(unescaped) => self.check_char_refs(unescaped),
+            Err(_) if self.lenient => {
+                self.warn("Found invalid entity");
+                Ok(unescape_lossy(&self.buf, false))
+            }
+            Err(_) => Err(ParserError { line: self.line, col: self.col, msg: "Found invalid entity" })
+        }
+    }
+
     fn error(&self, msg: &'static str) -> Result<Option<Event>, ParserError> {
-        Err(ParserError { line: self.line, col: self.col, msg: msg })
+        Err(ParserError { line: self.line, col: self.col, msg })
+    }
+
+    fn warn(&mut self, msg: &'static str) {
+        if !self.html {
+            let warning = ParserError { line: self.line, col: self.col, msg };
+            self.warnings.push(warning);
+        }
+    }
+
+    // Leave all namespace scopes, keeping only the standard namespaces
+    fn reset_namespaces(&mut self) {
+        self.namespaces.truncate(1);
+        match self.namespaces.first_mut() {
+            Some(scope) => scope.retain(|prefix, _| prefix == "xml" || prefix == "xmlns"),
+            None => self.namespaces.push(HashMap::with_capacity(2))
+        }
+        add_standard_namespaces(&mut self.namespaces[0]);
+    }
+
+    // Queue the ElementEnd event for the innermost open element, leaving its namespace scope.
+    fn close_element(&mut self, prefix: Option<String>, name: String) -> Result<(), ParserError> {
+        let ns = self.resolve_prefix(prefix.as_ref().map(|x| &x[..]),
+                                     "Unbound namespace prefix in tag name")?;
+        // The outermost scope holds the standard namespaces, a stray end tag must not remove it
+        let scope = if self.namespaces.len() > 1 { self.namespaces.pop() } else { None };
+        self.open.pop();
+        self.root_closed = self.open.is_empty();
+        self.pending.push_back(Event::ElementEnd(EndTag { name, ns, prefix }));
+
+        if self.prefix_mappings {
+            let mut prefixes: Vec<_> = scope.into_iter().flat_map(|x| x.into_iter()).collect();
+            prefixes.sort();
+            for (prefix, _) in prefixes {
+                let prefix = if prefix.is_empty() { None } else { Some(prefix) };
+                self.pending.push_back(Event::EndPrefixMapping { prefix });
+            }
+        }
+
+        if self.root_closed && self.multiple_documents {
+            self.pending.push_back(Event::EndDocument);
+            self.reset_namespaces();
+            self.version = XmlVersion::Version10;
+        }
+        Ok(())
+    }
+
+    // Handle an end tag. In lenient mode elements left open inside the closed element
+    // are closed implicitly, and end tags not matching any open element are dropped.
+    fn end_element(&mut self, prefix: Option<String>,
+                   name: String) -> Result<Option<Event>, ParserError> {
+        if self.lenient || self.html {
+            let matching = self.open.iter().rposition(|open| open.0 == prefix && open.1 == name);
+            let idx = match matching {
+                Some(idx) => idx,
+                None => {
+                    self.warn("Ignored end tag without matching start tag");
+                    return Ok(None);
+                }
+            };
+            while self.open.len() > idx + 1 {
+                self.warn("Closed unclosed element");
+                let (p, n) = self.open.last().cloned().expect("Internal error: No open element");
+                self.close_element(p, n)?;
+            }
+        }
+
+        self.close_element(prefix, name)?;
+        Ok(self.pending.pop_front())
+    }
+
+    // Queue the end of open elements implied by a start tag (HTML mode only),
+    // along with the elements open inside them
+    fn end_implied_elements(&mut self, start: &str) -> Result<(), ParserError> {
+        loop {
+            let found = self.open.iter().rposition(|x| {
+                implies_end(&x.1, start) || is_scope_boundary(&x.1, start)
+            });
+            let idx = match found {
+                Some(idx) if implies_end(&self.open[idx].1, start) => idx,
+                _ => return Ok(())
+            };
+            while self.open.len() > idx {
+                let (prefix, name) = self.open.last().cloned()
+                                         .expect("Internal error: No open element");
+                self.close_element(prefix, name)?;
+            }
+        }
     }
 
     fn parse_character(&mut self, c: char) -> Result<Option<Event>, ParserError> {
@@ -230,6 +1410,7 @@ impl Parser {
             State::InComment1 => self.in_comment1(c),
             State::InComment2 => self.in_comment2(c),
             State::InDoctype => self.in_doctype(c),
+            State::InRawText => self.in_raw_text(c),
         }
     }
 
@@ -240,10 +1421,10 @@ impl Parser {
             '<' if self.buf.is_empty() => self.st = State::TagOpened,
             '<' => {
                 self.st = State::TagOpened;
-                let buf = match unescape(&self.buf) {
-                    Ok(unescaped) => unescaped,
-                    Err(_) => return self.error("Found invalid entity")
-                };
+                if self.entity_refs {
+                    return self.text_with_entity_refs();
+                }
+                let buf = self.unescape_buf()?;
                 self.buf.truncate(0);
                 return Ok(Some(Event::Characters(buf)));
             }
@@ -252,6 +1433,63 @@ impl Parser {
         Ok(None)
     }
 
+    // Split the character data in buf at references to unknown entities,
+    // producing Event::Characters and Event::EntityRef.
+    // If the data is invalid only the error is returned, none of its events.
+    fn text_with_entity_refs(&mut self) -> Result<Option<Event>, ParserError> {
+        let queued = self.pending.len();
+        match self.queue_text_with_entity_refs() {
+            Ok(()) => Ok(self.pending.pop_front()),
+            Err(err) => {
+                self.pending.truncate(queued);
+                Err(err)
+            }
+        }
+    }
+
+    fn queue_text_with_entity_refs(&mut self) -> Result<(), ParserError> {
+        let buf = mem::take(&mut self.buf);
+        let mut text = String::with_capacity(buf.len());
+
+        let mut it = buf.split('&');
+        if let Some(sub) = it.next() {
+            text.push_str(sub);
+        }
+
+        for sub in it {
+            let ent = match sub.find(';') {
+                Some(idx) if is_name(&sub[..idx]) => &sub[..idx],
+                _ => {
+                    // Not a reference to an entity, let unescaping handle the error
+                    self.buf.push('&');
+                    self.buf.push_str(sub);
+                    text.push_str(&self.unescape_buf()?);
+                    self.buf.truncate(0);
+                    continue;
+                }
+            };
+            match unescape_entity(ent).map(|c| c.to_string())
+                                      .or_else(|| html_entity(ent).filter(|_| self.html)
+                                                                  .map(|s| s.to_owned())) {
+                Some(s) => text.push_str(&s),
+                None => {
+                    if !text.is_empty() {
+                        let text = self.check_char_refs(mem::take(&mut text))?;
+                        self.pending.push_back(Event::Characters(text));
+                    }
+                    self.pending.push_back(Event::EntityRef(ent.to_owned()));
+                }
+            }
+            text.push_str(&sub[ent.len()+1..]);
+        }
+
+        if !text.is_empty() {
+            let text = self.check_char_refs(text)?;
+            self.pending.push_back(Event::Characters(text));
+        }
+        Ok(())
+    }
+
     // Character following a '<', starting a tag or other construct
     // '?' => InProcessingInstructions
     // '!' => InExclamationMark
@@ -262,6 +1500,12 @@ impl Parser {
             '?' => State::InProcessingInstructions,
             '!' => State::InExclamationMark,
             '/' => State::InCloseTagName,
+            // A '<' not starting a tag is text in HTML
+            _ if self.html && !c.is_alphabetic() => {
+                self.buf.push('<');
+                self.st = State::OutsideTag;
+                return self.outside_tag(c);
+            }
             _ => {
                 self.buf.push(c);
                 State::InTagName
@@ -283,6 +1527,11 @@ impl Parser {
                 self.st = State::OutsideTag;
                 let _ = self.buf.pop();
                 let buf = mem::replace(&mut self.buf, String::new());
+                if self.open.is_empty() && buf.starts_with("xml") {
+                    if let Some(version) = xml_decl_version(&buf[3..]) {
+                        self.version = version;
+                    }
+                }
                 return Ok(Some(Event::PI(buf)));
             }
             _ => self.buf.push(c)
@@ -298,38 +1547,14 @@ impl Parser {
         match c {
             '/'
             | '>' => {
-                let (prefix, name) = parse_qname(&self.buf);
-                self.buf.truncate(0);
-                let ns = match prefix {
-                    None => self.namespace_for_prefix(""),
-                    Some(ref pre) => match self.namespace_for_prefix(&pre) {
-                        None => return self.error("Unbound namespace prefix in tag name"),
-                        ns => ns
-                    }
-                };
-
-                self.namespaces.push(HashMap::new());
-                self.st = if c == '/' {
-                    self.name = Some((prefix.clone(), name.clone()));
-                    State::ExpectClose
-                } else {
-                    State::OutsideTag
-                };
-
-                return Ok(Some(Event::ElementStart(StartTag {
-                    name: name,
-                    ns: ns,
-                    prefix: prefix,
-                    attributes: HashMap::new()
-                })));
+                self.start_tag_name()?;
+                return self.in_tag(c);
             }
             ' '
             | '\t'
             | '\r'
             | '\n' => {
-                self.namespaces.push(HashMap::new());
-                self.name = Some(parse_qname(&self.buf));
-                self.buf.truncate(0);
+                self.start_tag_name()?;
                 self.st = State::InTag;
             }
             _ => self.buf.push(c)
@@ -337,6 +1562,19 @@ impl Parser {
         Ok(None)
     }
 
+    // The name of a start tag is complete, enter the element's namespace scope
+    fn start_tag_name(&mut self) -> Result<(), ParserError> {
+        self.check_name()?;
+        let (prefix, name) = self.parse_name(&self.buf);
+        self.buf.truncate(0);
+        if self.html {
+            self.end_implied_elements(&name)?;
+        }
+        self.namespaces.push(HashMap::new());
+        self.name = Some((prefix, name));
+        Ok(())
+    }
+
     // Inside a tag name (closing tag)
     // '>' => OutsideTag, producing ElementEnd
     // ' ' or '\t' or '\r' or '\n' => ExpectSpaceOrClose, producing ElementEnd
@@ -347,25 +1585,17 @@ impl Parser {
             | '\r'
             | '\n'
             | '>' => {
-                let (prefix, name) = parse_qname(&self.buf);
+                self.check_name()?;
+                let (prefix, name) = self.parse_name(&self.buf);
                 self.buf.truncate(0);
 
-                let ns = match prefix {
-                    None => self.namespace_for_prefix(""),
-                    Some(ref pre) => match self.namespace_for_prefix(&pre) {
-                        None => return self.error("Unbound namespace prefix in tag name"),
-                        ns => ns
-                    }
-                };
-
-                self.namespaces.pop();
                 self.st = if c == '>' {
                     State::OutsideTag
                 } else {
                     State::ExpectSpaceOrClose
                 };
 
-                Ok(Some(Event::ElementEnd(EndTag { name: name, ns: ns, prefix: prefix })))
+                self.end_element(prefix, name)
             }
             _ => {
                 self.buf.push(c);
@@ -384,44 +1614,70 @@ impl Parser {
             | '>' => {
                 let attributes = mem::replace(&mut self.attributes, Vec::new());
                 let (prefix, name) = self.name.take().expect("Internal error: No element name set");
-                let ns = match prefix {
-                    None => self.namespace_for_prefix(""),
-                    Some(ref pre) => match self.namespace_for_prefix(&pre) {
-                        None => return self.error("Unbound namespace prefix in tag name"),
-                        ns => ns
-                    }
-                };
+                let ns = self.resolve_prefix(prefix.as_ref().map(|x| &x[..]),
+                                             "Unbound namespace prefix in tag name")?;
 
-                let mut attributes_map: HashMap<(String, Option<String>), String> = HashMap::new();
+                let mut attributes_map = Attributes::with_capacity(attributes.len());
 
                 // At this point attribute namespaces are really just prefixes,
                 // map them to the actual namespace
                 for (name, ns, value) in attributes {
                     let ns = match ns {
                         None => None,
-                        Some(ref prefix) => match self.namespace_for_prefix(&prefix) {
-                            None => return self.error("Unbound namespace prefix in attribute name"),
-                            ns => ns
+                        Some(ref prefix) => {
+                            self.resolve_prefix(Some(prefix),
+                                                "Unbound namespace prefix in attribute name")?
                         }
                     };
-                    if attributes_map.insert((name, ns), value).is_some() {
+                    let key = (name, ns);
+                    if !attributes_map.contains_key(&key) {
+                        attributes_map.insert(key, value);
+                    } else if !self.html {
+                        // HTML ignores all but the first occurrence
                         return self.error("Duplicate attribute");
                     }
                 }
 
+                // Declare placeholder namespaces bound in lenient mode
+                if self.lenient {
+                    let xmlns = Some("http://www.w3.org/2000/xmlns/".to_owned());
+                    let scope = self.namespaces.last().expect("Internal error: Empty namespace stack");
+                    let mut unbound: Vec<_> = scope.iter()
+                                                   .filter(|&(_, ns)| ns.starts_with(UNBOUND_NS))
+                                                   .collect();
+                    unbound.sort();
+                    for (pre, ns) in unbound {
+                        let key = (pre.clone(), xmlns.clone());
+                        if !attributes_map.contains_key(&key) {
+                            attributes_map.insert(key, ns.clone());
+                        }
+                    }
+                }
+                if self.prefix_mappings || self.strip_ns_decls {
+                    self.report_namespace_declarations(&mut attributes_map);
+                }
+                self.open.push((prefix.clone(), name.clone()));
+
+                let void = self.html && c == '>' && is_void_element(&name);
                 self.st = if c == '/' {
                     self.name = Some((prefix.clone(), name.clone()));
                     State::ExpectClose
+                } else if self.html && is_raw_text_element(&name) {
+                    State::InRawText
                 } else {
                     State::OutsideTag
                 };
 
-                return Ok(Some(Event::ElementStart(StartTag {
-                    name: name,
-                    ns: ns,
-                    prefix: prefix,
+                self.pending.push_back(Event::ElementStart(StartTag {
+                    name: name.clone(),
+                    ns,
+                    prefix: prefix.clone(),
                     attributes: attributes_map
-                })));
+                }));
+                if void {
+                    self.close_element(prefix, name)?;
+                }
+                return Ok(self.pending.pop_front());
             }
             ' '
             | '\t'
@@ -435,13 +1691,44 @@ impl Parser {
         Ok(None)
     }
 
+    // Queue StartPrefixMapping events for the namespace declarations among a start tag's
+    // attributes, and remove them if requested
+    fn report_namespace_declarations(&mut self, attributes: &mut Attributes) {
+        if self.html || !self.namespace_aware {
+            return;
+        }
+        let decls: Vec<_> = attributes.iter().filter_map(|(key, value)| match *key {
+            (ref name, None) if name == "xmlns" => Some((None, value.clone())),
+            (ref name, Some(ref ns)) if ns == "http://www.w3.org/2000/xmlns/" => {
+                Some((Some(name.clone()), value.clone()))
+            }
+            _ => None
+        }).collect();
+
+        for (prefix, uri) in decls {
+            if self.strip_ns_decls {
+                let key = match prefix {
+                    None => ("xmlns".to_owned(), None),
+                    Some(ref prefix) => {
+                        (prefix.clone(), Some("http://www.w3.org/2000/xmlns/".to_owned()))
+                    }
+                };
+                attributes.remove(&key);
+            }
+            if self.prefix_mappings {
+                self.pending.push_back(Event::StartPrefixMapping { prefix, uri });
+            }
+        }
+    }
+
     // Inside an attribute name
     // '=' => ExpectDelimiter
     fn in_attr_name(&mut self, c: char) -> Result<Option<Event>, ParserError> {
         match c {
             '=' => {
+                self.check_name()?;
                 self.level = 0;
-                self.attr = Some(parse_qname(&self.buf));
+                self.attr = Some(self.parse_attr_name(&self.buf));
                 self.buf.truncate(0);
                 self.st = State::ExpectDelimiter;
             }
@@ -449,7 +1736,19 @@ impl Parser {
             | '\t'
             | '\r'
             | '\n' => self.level = 1,
+            // Attributes without value in HTML
+            '/'
+            | '>' if self.html => {
+                self.add_empty_attribute()?;
+                self.st = State::InTag;
+                return self.in_tag(c);
+            }
             _ if self.level == 0 => self.buf.push(c),
+            _ if self.html => {
+                self.add_empty_attribute()?;
+                self.level = 0;
+                self.buf.push(c);
+            }
             _ => return self.error("Space occured in attribute name")
         }
         Ok(None)
@@ -457,35 +1756,65 @@ impl Parser {
 
     // Inside an attribute value
     // delimiter => InTag, adds attribute
+    // Unquoted values (lenient mode only):
+    // ' ' or '\t' or '\r' or '\n' => InTag, adds attribute
+    // '>' => OutsideTag, adds attribute, producing StartTag
     fn in_attr_value(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        if c == self.delim.expect("Internal error: In attribute value, but no delimiter set") {
-            self.delim = None;
-            self.st = State::InTag;
-            let attr = self.attr.take();
-            let (prefix, name) =
-                attr.expect("Internal error: In attribute value, but no attribute name set");
-            let value = match unescape(&self.buf) {
-                Ok(unescaped) => unescaped,
-                Err(_) => return self.error("Found invalid entity")
-            };
-            self.buf.truncate(0);
-
-            let last = self.namespaces.last_mut().expect("Internal error: Empty namespace stack");
-            match prefix {
-                None if name == "xmlns" => {
-                    last.insert(String::new(), value.clone());
-                }
-                Some(ref prefix) if prefix == "xmlns" => {
-                    last.insert(name.clone(), value.clone());
-                }
-                _ => ()
+        match (self.delim, c) {
+            (Some(delim), c) if c == delim => {
+                self.delim = None;
+                self.st = State::InTag;
+                self.add_attribute()?;
+            }
+            (None, ' ')
+            | (None, '\t')
+            | (None, '\r')
+            | (None, '\n') => {
+                self.st = State::InTag;
+                self.add_attribute()?;
             }
+            (None, '>') => {
+                self.st = State::InTag;
+                self.add_attribute()?;
+                return self.in_tag(c);
+            }
+            _ => self.buf.push(c)
+        }
+        Ok(None)
+    }
 
+    // Add the attribute whose name is in buf with an empty value
+    fn add_empty_attribute(&mut self) -> Result<(), ParserError> {
+        self.attr = Some(self.parse_attr_name(&self.buf));
+        self.buf.truncate(0);
+        self.add_attribute()
+    }
+
+    // Add the attribute whose value is in buf, binding namespaces it declares
+    fn add_attribute(&mut self) -> Result<(), ParserError> {
+        let attr = self.attr.take();
+        let (prefix, name) =
+            attr.expect("Internal error: In attribute value, but no attribute name set");
+        let value = self.unescape_buf()?;
+        self.buf.truncate(0);
+        if self.html || !self.namespace_aware {
             self.attributes.push((name, prefix, value));
-        } else {
-            self.buf.push(c);
+            return Ok(());
         }
-        Ok(None)
+
+        let last = self.namespaces.last_mut().expect("Internal error: Empty namespace stack");
+        match prefix {
+            None if name == "xmlns" => {
+                last.insert(String::new(), value.clone());
+            }
+            Some(ref prefix) if prefix == "xmlns" => {
+                last.insert(name.clone(), value.clone());
+            }
+            _ => ()
+        }
+
+        self.attributes.push((name, prefix, value));
+        Ok(())
     }
 
     // Looking for an attribute value delimiter
@@ -501,6 +1830,11 @@ impl Parser {
             | '\t'
             | '\r'
             | '\n' => (),
+            _ if self.lenient || self.html => {
+                self.warn("Attribute value not enclosed in ' or \"");
+                self.buf.push(c);
+                self.st = State::InAttrValue;
+            }
             _ => return self.error("Attribute value not enclosed in ' or \"")
         }
         Ok(None)
@@ -513,15 +1847,8 @@ impl Parser {
             '>' => {
                 self.st = State::OutsideTag;
                 let (prefix, name) = self.name.take().expect("Internal error: No element name set");
-                let ns = match prefix {
-                    None => self.namespace_for_prefix(""),
-                    Some(ref pre) => match self.namespace_for_prefix(&pre) {
-                        None => return self.error("Unbound namespace prefix in tag name"),
-                        ns => ns
-                    }
-                };
-                self.namespaces.pop();
-                Ok(Some(Event::ElementEnd(EndTag { name: name, ns: ns, prefix: prefix })))
+                self.close_element(prefix, name)?;
+                Ok(self.pending.pop_front())
             }
             _ => self.error("Expected '>' to close tag")
        }
@@ -552,6 +1879,7 @@ impl Parser {
             '-' => State::InCommentOpening,
             '[' => State::InCDATAOpening,
             'D' => State::InDoctype,
+            'd' if self.html => State::InDoctype,
             _ => return self.error("Malformed XML")
         };
         Ok(None)
@@ -648,7 +1976,8 @@ impl Parser {
     fn in_doctype(&mut self, c: char) -> Result<Option<Event>, ParserError> {
         static DOCTYPE_PATTERN: [char; 6] = ['O', 'C', 'T', 'Y', 'P', 'E'];
         match self.level {
-            0...5 => if c == DOCTYPE_PATTERN[self.level as usize] {
+            0...5 => if c == DOCTYPE_PATTERN[self.level as usize]
+                        || self.html && c.to_ascii_uppercase() == DOCTYPE_PATTERN[self.level as usize] {
                 self.level += 1;
             } else {
                 return self.error("Invalid DOCTYPE");
@@ -671,27 +2000,52 @@ impl Parser {
         }
         Ok(None)
     }
+
+    // Inside the content of a raw text element (HTML mode only)
+    // '<' '/' name '>' => OutsideTag, producing Characters and ElementEnd
+    fn in_raw_text(&mut self, c: char) -> Result<Option<Event>, ParserError> {
+        if c == '>' {
+            let (prefix, name) = self.open.last().cloned().expect("Internal error: No open element");
+            let end_tag = format!("</{}", name);
+            let end = {
+                let content = self.buf.trim_end();
+                content.len().checked_sub(end_tag.len()).filter(|&idx| {
+                    content.is_char_boundary(idx) && content[idx..].eq_ignore_ascii_case(&end_tag)
+                })
+            };
+            if let Some(idx) = end {
+                self.buf.truncate(idx);
+                self.st = State::OutsideTag;
+                let text = mem::take(&mut self.buf);
+                if !text.is_empty() {
+                    self.pending.push_back(Event::Characters(text));
+                }
+                self.close_element(prefix, name)?;
+                return Ok(self.pending.pop_front());
+            }
+        }
+        self.buf.push(c);
+        Ok(None)
+    }
 }
 
 #[cfg(test)]
 mod parser_tests {
-    use std::collections::HashMap;
-
-    use super::Parser;
-    use super::super::{Event, ParserError, StartTag, EndTag};
+    use super::{ParseStatus, Parser, WhitespaceMode};
+    use super::super::{Attributes, Event, ParserError, StartTag, EndTag, XmlVersion};
 
     #[test]
     fn test_start_tag() {
         let mut p = Parser::new();
         let mut i = 0u8;
         p.feed_str("<a>");
-        for event in p {
+        for event in &mut p {
             i += 1;
             assert_eq!(event, Ok(Event::ElementStart(StartTag {
                 name: "a".to_owned(),
                 ns: None,
                 prefix: None,
-                attributes: HashMap::new()
+                attributes: Attributes::new()
             })));
         }
         assert_eq!(i, 1u8);
@@ -702,7 +2056,7 @@ mod parser_tests {
         let mut p = Parser::new();
         let mut i = 0u8;
         p.feed_str("</a>");
-        for event in p {
+        for event in &mut p {
             i += 1;
             assert_eq!(event, Ok(Event::ElementEnd(EndTag {
                 name: "a".to_owned(),
@@ -724,7 +2078,7 @@ mod parser_tests {
                 name: "register".to_owned(),
                 ns: None,
                 prefix: None,
-                attributes: HashMap::new()
+                attributes: Attributes::new()
             })),
             Ok(Event::ElementEnd(EndTag {
                 name: "register".to_owned(),
@@ -745,7 +2099,7 @@ mod parser_tests {
                 name: "register".to_owned(),
                 ns: None,
                 prefix: None,
-                attributes: HashMap::new()
+                attributes: Attributes::new()
             })),
             Ok(Event::ElementEnd(EndTag {
                 name: "register".to_owned(),
@@ -761,7 +2115,7 @@ mod parser_tests {
         p.feed_str("<foo:a xmlns:foo='urn:foo'/>");
 
         let v: Vec<Result<Event, ParserError>> = p.collect();
-        let mut attr: HashMap<(String, Option<String>), String> = HashMap::new();
+        let mut attr = Attributes::new();
         attr.insert(("foo".to_owned(), Some("http://www.w3.org/2000/xmlns/".to_owned())),
                     "urn:foo".to_owned());
         assert_eq!(v, vec![
@@ -784,7 +2138,7 @@ mod parser_tests {
         let mut p = Parser::new();
         let mut i = 0u8;
         p.feed_str("<?xml version='1.0' encoding='utf-8'?>");
-        for event in p {
+        for event in &mut p {
             i += 1;
             assert_eq!(event, Ok(Event::PI("xml version='1.0' encoding='utf-8'".to_owned())));
         }
@@ -796,7 +2150,7 @@ mod parser_tests {
         let mut p = Parser::new();
         let mut i = 0u8;
         p.feed_str("<!--Nothing to see-->");
-        for event in p {
+        for event in &mut p {
             i += 1;
             assert_eq!(event, Ok(Event::Comment("Nothing to see".to_owned())));
         }
@@ -807,7 +2161,7 @@ mod parser_tests {
         let mut p = Parser::new();
         let mut i = 0u8;
         p.feed_str("<![CDATA[<html><head><title>x</title></head><body/></html>]]>");
-        for event in p {
+        for event in &mut p {
             i += 1;
             assert_eq!(event,
                        Ok(Event::CDATA("<html><head><title>x</title></head><body/></html>".to_owned())));
@@ -820,7 +2174,7 @@ mod parser_tests {
         let mut p = Parser::new();
         let mut i = 0u8;
         p.feed_str("<text>Hello World, it&apos;s a nice day</text>");
-        for event in p {
+        for event in &mut p {
             i += 1;
             if i == 2 {
                 assert_eq!(event,
@@ -835,9 +2189,655 @@ mod parser_tests {
         let mut p = Parser::new();
         let mut i = 0u8;
         p.feed_str("<!DOCTYPE html>");
-        for _ in p {
+        for _ in &mut p {
             i += 1;
         }
         assert_eq!(i, 0u8);
     }
+
+    #[test]
+    fn test_lenient_entities() {
+        let mut p = Parser::new();
+        p.set_lenient(true);
+        p.feed_str("<a t='R&D'>fish & chips&nbsp;&amp; more</a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        let mut attr = Attributes::new();
+        attr.insert(("t".to_owned(), None), "R&D".to_owned());
+        assert_eq!(v, vec![
+            Ok(Event::ElementStart(StartTag {
+                name: "a".to_owned(),
+                ns: None,
+                prefix: None,
+                attributes: attr
+            })),
+            Ok(Event::Characters("fish & chips&nbsp;& more".to_owned())),
+            Ok(Event::ElementEnd(EndTag {
+                name: "a".to_owned(),
+                ns: None,
+                prefix: None
+            }))
+        ]);
+        assert_eq!(p.warnings().len(), 2);
+        assert_eq!(p.warnings()[0].msg, "Found invalid entity");
+    }
+
+    #[test]
+    fn test_invalid_entity() {
+        let mut p = Parser::new();
+        p.feed_str("<a>fish & chips</a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[1], Err(ParserError { line: 1, col: 16, msg: "Found invalid entity" }));
+    }
+
+    #[test]
+    fn test_lenient_unquoted_attributes() {
+        let mut p = Parser::new();
+        p.set_lenient(true);
+        p.feed_str("<a href=http://example.com/ rel='x' id=1>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        let mut attr = Attributes::new();
+        attr.insert(("href".to_owned(), None), "http://example.com/".to_owned());
+        attr.insert(("rel".to_owned(), None), "x".to_owned());
+        attr.insert(("id".to_owned(), None), "1".to_owned());
+        assert_eq!(v, vec![
+            Ok(Event::ElementStart(StartTag {
+                name: "a".to_owned(),
+                ns: None,
+                prefix: None,
+                attributes: attr
+            }))
+        ]);
+        assert_eq!(p.warnings().len(), 2);
+    }
+
+    #[test]
+    fn test_lenient_unbound_prefix() {
+        let mut p = Parser::new();
+        p.set_lenient(true);
+        p.feed_str("<foo:a bar:x='1'/>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        let xmlns = Some("http://www.w3.org/2000/xmlns/".to_owned());
+        let mut attr = Attributes::new();
+        attr.insert(("x".to_owned(), Some("urn:rustyxml:unbound:bar".to_owned())), "1".to_owned());
+        attr.insert(("foo".to_owned(), xmlns.clone()), "urn:rustyxml:unbound:foo".to_owned());
+        attr.insert(("bar".to_owned(), xmlns), "urn:rustyxml:unbound:bar".to_owned());
+        assert_eq!(v, vec![
+            Ok(Event::ElementStart(StartTag {
+                name: "a".to_owned(),
+                ns: Some("urn:rustyxml:unbound:foo".to_owned()),
+                prefix: Some("foo".to_owned()),
+                attributes: attr
+            })),
+            Ok(Event::ElementEnd(EndTag {
+                name: "a".to_owned(),
+                ns: Some("urn:rustyxml:unbound:foo".to_owned()),
+                prefix: Some("foo".to_owned())
+            }))
+        ]);
+        assert_eq!(p.warnings().len(), 2);
+    }
+
+    #[test]
+    fn test_lenient_unclosed_elements() {
+        let mut p = Parser::new();
+        p.set_lenient(true);
+        p.feed_str("<a><b><c></x></a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        let names: Vec<_> = v.into_iter().map(|e| match e {
+            Ok(Event::ElementStart(tag)) => format!("<{}>", tag.name),
+            Ok(Event::ElementEnd(tag)) => format!("</{}>", tag.name),
+            e => panic!("Unexpected event: {:?}", e)
+        }).collect();
+        assert_eq!(names, vec!["<a>", "<b>", "<c>", "</c>", "</b>", "</a>"]);
+        assert_eq!(p.warnings().len(), 3);
+    }
+
+    #[test]
+    fn test_html() {
+        let mut p = Parser::new();
+        p.set_html(true);
+        p.feed_str("<!doctype html><P CLASS=x>One<p>Two<BR>x &lt; y<ul><li>a<li>b</ul>\
+                    <input disabled><script>if (a<b && c) {}</SCRIPT >&nbsp;&copy;&bogus;</body>");
+
+        let v: Vec<String> = p.map(|e| match e {
+            Ok(Event::ElementStart(tag)) => {
+                let mut attrs: Vec<_> = tag.attributes.iter()
+                                           .map(|(k, v)| format!(" {}='{}'", k.0, v))
+                                           .collect();
+                attrs.sort();
+                format!("<{}{}>", tag.name, attrs.concat())
+            }
+            Ok(Event::ElementEnd(tag)) => format!("</{}>", tag.name),
+            Ok(Event::Characters(text)) => text,
+            e => panic!("Unexpected event: {:?}", e)
+        }).collect();
+        assert_eq!(v, vec!["<p class='x'>", "One", "</p>", "<p>", "Two", "<br>", "</br>", "x < y",
+                           "</p>", "<ul>", "<li>", "a", "</li>", "<li>", "b", "</li>", "</ul>",
+                           "<input disabled=''>", "</input>", "<script>", "if (a<b && c) {}",
+                           "</script>", "\u{a0}\u{a9}&bogus;"]);
+        assert!(p.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_html_table() {
+        let mut p = Parser::new();
+        p.set_html(true);
+        p.feed_str("<table><tr><td>1<td>2<tr><th>3</table>");
+
+        let v: Vec<String> = p.map(|e| match e {
+            Ok(Event::ElementStart(tag)) => format!("<{}>", tag.name),
+            Ok(Event::ElementEnd(tag)) => format!("</{}>", tag.name),
+            Ok(Event::Characters(text)) => text,
+            e => panic!("Unexpected event: {:?}", e)
+        }).collect();
+        assert_eq!(v.concat(), "<table><tr><td>1</td><td>2</td></tr><tr><th>3</th></tr></table>");
+    }
+
+    #[test]
+    fn test_html_implied_end_scope() {
+        let mut p = Parser::new();
+        p.set_html(true);
+        p.feed_str("<p><b>x<div>y</div><ul><li><i>a<li>b<table><tr><td><li>c</table></ul>");
+
+        let v: Vec<String> = p.map(|e| match e {
+            Ok(Event::ElementStart(tag)) => format!("<{}>", tag.name),
+            Ok(Event::ElementEnd(tag)) => format!("</{}>", tag.name),
+            Ok(Event::Characters(text)) => text,
+            e => panic!("Unexpected event: {:?}", e)
+        }).collect();
+        assert_eq!(v.concat(), "<p><b>x</b></p><div>y</div><ul><li><i>a</i></li><li>b<table><tr>\
+                                <td><li>c</li></td></tr></table></li></ul>");
+    }
+
+    #[test]
+    fn test_namespace_unaware() {
+        let mut p = Parser::new();
+        p.set_namespace_aware(false);
+        p.feed_str("<foo:a xmlns:bar='urn:bar' bar:x='1'></foo:a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        let mut attr = Attributes::new();
+        attr.insert(("xmlns:bar".to_owned(), None), "urn:bar".to_owned());
+        attr.insert(("bar:x".to_owned(), None), "1".to_owned());
+        assert_eq!(v, vec![
+            Ok(Event::ElementStart(StartTag {
+                name: "a".to_owned(),
+                ns: None,
+                prefix: Some("foo".to_owned()),
+                attributes: attr
+            })),
+            Ok(Event::ElementEnd(EndTag {
+                name: "a".to_owned(),
+                ns: None,
+                prefix: Some("foo".to_owned())
+            }))
+        ]);
+        assert_eq!(p.namespaces.len(), 1);
+        assert_eq!(p.namespaces[0].len(), 2);
+    }
+
+    #[test]
+    fn test_entity_refs() {
+        let mut p = Parser::new();
+        p.set_preserve_entity_refs(true);
+        p.feed_str("<a>x&lt;&nbsp;&#65;&foo;&bar;y</a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(&v[1..6], &[
+            Ok(Event::Characters("x<".to_owned())),
+            Ok(Event::EntityRef("nbsp".to_owned())),
+            Ok(Event::Characters("A".to_owned())),
+            Ok(Event::EntityRef("foo".to_owned())),
+            Ok(Event::EntityRef("bar".to_owned()))
+        ]);
+        assert_eq!(v[6], Ok(Event::Characters("y".to_owned())));
+        assert_eq!(v.len(), 8);
+
+        let mut p = Parser::new();
+        p.set_preserve_entity_refs(true);
+        p.feed_str("<a>&nbsp;&</a>");
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[1], Err(ParserError { line: 1, col: 11, msg: "Found invalid entity" }));
+        assert_eq!(v.len(), 2);
+
+        // No events are queued for text with an invalid reference following valid ones
+        let mut p = Parser::new();
+        p.set_preserve_entity_refs(true);
+        p.feed_str("<a>x&foo;y&#0;z</a>");
+        let v: Vec<Result<Event, ParserError>> = (&mut p).collect();
+        assert_eq!(v.len(), 2);
+        assert!(v[1].is_err());
+        assert!(p.pending.is_empty());
+        p.reset(false);
+        p.feed_str("<b/>");
+        assert_eq!(p.count(), 2);
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let mut p = Parser::new();
+        p.feed_str("<a>x\r\ny\rz\u{85}\r");
+        p.feed_str("\n</a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[1], Ok(Event::Characters("x\ny\nz\u{85}\n".to_owned())));
+        assert_eq!(p.line, 4);
+    }
+
+    #[test]
+    fn test_xml11() {
+        let mut p = Parser::new();
+        p.feed_str("<?xml version='1.1'?><a>x&#1;y\u{85}z\r\u{85}\u{2028}</a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[2], Ok(Event::Characters("x\u{1}y\nz\n\n".to_owned())));
+        assert_eq!(p.version(), XmlVersion::Version11);
+
+        let mut p = Parser::new();
+        p.feed_str("<?xml version=\"1.1\"?><a>\u{80}</a>");
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[2], Err(ParserError { line: 1, col: 25, msg: "Invalid character" }));
+    }
+
+    #[test]
+    fn test_xml10_chars() {
+        let mut p = Parser::new();
+        p.feed_str("<a>&#1;</a>");
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[1], Err(ParserError { line: 1, col: 8, msg: "Invalid character reference" }));
+        assert_eq!(p.version(), XmlVersion::Version10);
+
+        let mut p = Parser::new();
+        p.feed_str("<a>\u{1}</a>");
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[1], Err(ParserError { line: 1, col: 4, msg: "Invalid character" }));
+
+        let mut p = Parser::new();
+        p.feed_str("<a>\u{80}</a>");
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[1], Ok(Event::Characters("\u{80}".to_owned())));
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        let mut p = Parser::new();
+        p.feed_str("<a><1b/></a>");
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[1], Err(ParserError { line: 1, col: 7, msg: "Invalid name" }));
+
+        let mut p = Parser::new();
+        p.feed_str("<\u{e9}l\u{e8}ve x-y.z\u{b7}='1'/>");
+        assert!((&mut p).all(|x| x.is_ok()));
+    }
+
+    #[test]
+    fn test_prefix_mappings() {
+        let mut p = Parser::new();
+        p.set_report_prefix_mappings(true);
+        p.set_strip_namespace_declarations(true);
+        p.feed_str("<a xmlns:b='urn:b' xmlns='urn:a' x='1'><b:c/></a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        let mut attr = Attributes::new();
+        attr.insert(("x".to_owned(), None), "1".to_owned());
+        assert_eq!(v, vec![
+            Ok(Event::StartPrefixMapping {
+                prefix: Some("b".to_owned()),
+                uri: "urn:b".to_owned()
+            }),
+            Ok(Event::StartPrefixMapping {
+                prefix: None,
+                uri: "urn:a".to_owned()
+            }),
+            Ok(Event::ElementStart(StartTag {
+                name: "a".to_owned(),
+                ns: Some("urn:a".to_owned()),
+                prefix: None,
+                attributes: attr
+            })),
+            Ok(Event::ElementStart(StartTag {
+                name: "c".to_owned(),
+                ns: Some("urn:b".to_owned()),
+                prefix: Some("b".to_owned()),
+                attributes: Attributes::new()
+            })),
+            Ok(Event::ElementEnd(EndTag {
+                name: "c".to_owned(),
+                ns: Some("urn:b".to_owned()),
+                prefix: Some("b".to_owned())
+            })),
+            Ok(Event::ElementEnd(EndTag {
+                name: "a".to_owned(),
+                ns: Some("urn:a".to_owned()),
+                prefix: None
+            })),
+            Ok(Event::EndPrefixMapping { prefix: None }),
+            Ok(Event::EndPrefixMapping { prefix: Some("b".to_owned()) })
+        ]);
+    }
+
+    fn text_events(p: &mut Parser) -> Vec<Event> {
+        p.filter_map(|e| match e {
+            Ok(Event::ElementStart(_))
+            | Ok(Event::ElementEnd(_)) => None,
+            e => Some(e.unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn test_coalesce_text() {
+        let mut p = Parser::new();
+        p.set_coalesce_text(true);
+        p.feed_str("<a>x<![CDATA[<y>]]>z&amp;<!--c-->w<b/><![CDATA[v]]></a>");
+        assert_eq!(text_events(&mut p), vec![
+            Event::Characters("x<y>z&".to_owned()),
+            Event::Comment("c".to_owned()),
+            Event::Characters("w".to_owned()),
+            Event::Characters("v".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn test_whitespace_mode() {
+        let input = "<a>\n  <b> x </b>\n</a>";
+
+        let mut p = Parser::new();
+        p.set_whitespace_mode(WhitespaceMode::Report);
+        p.feed_str(input);
+        assert_eq!(text_events(&mut p), vec![
+            Event::Whitespace("\n  ".to_owned()),
+            Event::Characters(" x ".to_owned()),
+            Event::Whitespace("\n".to_owned())
+        ]);
+
+        let mut p = Parser::new();
+        p.set_whitespace_mode(WhitespaceMode::Drop);
+        p.feed_str(input);
+        assert_eq!(text_events(&mut p), vec![Event::Characters(" x ".to_owned())]);
+
+        let mut p = Parser::new();
+        p.set_trim_text(true);
+        p.feed_str(input);
+        assert_eq!(text_events(&mut p), vec![Event::Characters("x".to_owned())]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut p = Parser::new();
+        p.feed_str("<stream:stream xmlns:stream='urn:s'><foo");
+        let _: Vec<_> = p.collect();
+        p.reset(false);
+        p.feed_str("<stream:stream xmlns:stream='urn:s'><a/>");
+        let v: Vec<_> = p.map(|x| x.unwrap()).collect();
+        assert_eq!(v.len(), 3);
+        assert_eq!(p.locator().col, 40);
+
+        p.reset(true);
+        p.feed_str("<b:c/>");
+        assert!((&mut p).next().unwrap().is_err());
+        p.reset(false);
+        p.feed_str("<b xmlns='urn:b'/>");
+        assert!((&mut p).next().unwrap().is_ok());
+
+        p.feed_str("<a/><b/>");
+        let _ = (&mut p).next();
+        p.reset(true);
+        assert_eq!((&mut p).next(), Some(Ok(Event::ElementStart(StartTag {
+            name: "a".to_owned(),
+            ns: None,
+            prefix: None,
+            attributes: Attributes::new()
+        }))));
+
+        // A stray end tag must not remove the standard namespaces
+        let mut p = Parser::new();
+        p.feed_str("</a>");
+        let _: Vec<_> = p.collect();
+        p.reset(false);
+        p.feed_str("<a xml:lang='en'/>");
+        let v: Vec<_> = p.collect();
+        assert_eq!(v.len(), 2);
+        assert!(v.iter().all(|x| x.is_ok()));
+    }
+
+    #[test]
+    fn test_stop_at_root_end() {
+        let mut p = Parser::new();
+        p.set_stop_at_root_end(true);
+        p.feed_str("<a><b/></a>\r\n\x16\x03<c/>");
+        assert_eq!(p.count(), 4);
+        assert_eq!(p.take_remaining(), "\r\n\x16\x03<c/>");
+        assert_eq!((&mut p).next(), None);
+
+        let mut p = Parser::new();
+        p.set_stop_at_root_end(true);
+        p.feed_bytes(b"<a/>\xc3").unwrap();
+        assert_eq!(p.count(), 2);
+        p.feed_bytes(b"\xa9\xe2\x82").unwrap();
+        assert_eq!(p.take_remaining_bytes(), b"\xc3\xa9\xe2\x82");
+
+        // Binary data following the root element is kept as fed
+        let mut p = Parser::new();
+        p.set_stop_at_root_end(true);
+        assert_eq!(p.feed_bytes(b"<a>\xc3\xa9</a>\x16\x03\x01\xfc\xc3"), Ok(14));
+        assert_eq!(p.feed_bytes(b"\x00\xff"), Ok(2));
+        assert_eq!(p.count(), 3);
+        assert_eq!(p.take_remaining_bytes(), b"\x16\x03\x01\xfc\xc3\x00\xff");
+        p.feed_bytes(b"<b/>").unwrap();
+        assert_eq!(p.count(), 0);
+
+        let mut p = Parser::new();
+        p.set_stop_at_root_end(true);
+        p.feed_bytes(b"<a>\xfc</a>").unwrap();
+        let v: Vec<_> = p.collect();
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[1].as_ref().unwrap_err().msg, "Invalid UTF-8");
+    }
+
+    #[test]
+    fn test_feed_bytes() {
+        let mut p = Parser::new();
+        p.feed_bytes(b"<a>\xe2\x82").unwrap();
+        p.feed_bytes(b"\xac</a>").unwrap();
+        let v: Vec<_> = p.collect();
+        assert_eq!(v[1], Ok(Event::Characters("\u{20ac}".to_owned())));
+
+        let mut p = Parser::new();
+        assert!(p.feed_bytes(b"<a>\xff</a>").is_err());
+        assert_eq!(p.take_remaining(), "<a>");
+    }
+
+    #[test]
+    fn test_multiple_documents() {
+        let mut p = Parser::new();
+        p.set_multiple_documents(true);
+        p.feed_str("<?xml version='1.1'?><a xmlns:x='urn:x'/>\n\
+                    <?xml version='1.0'?>\n<x:b/>");
+        let v: Vec<_> = p.collect();
+        assert_eq!(v[0], Ok(Event::StartDocument));
+        assert_eq!(v[1], Ok(Event::PI("xml version='1.1'".to_owned())));
+        assert_eq!(v[4], Ok(Event::EndDocument));
+        assert_eq!(v[5], Ok(Event::StartDocument));
+        assert_eq!(v[6], Ok(Event::PI("xml version='1.0'".to_owned())));
+        assert_eq!(v[7], Ok(Event::Characters("\n".to_owned())));
+        // The prefix declared in the first document is out of scope
+        assert!(v[8].is_err());
+        assert_eq!(p.version(), XmlVersion::Version10);
+
+        let mut p = Parser::new();
+        p.set_multiple_documents(true);
+        p.feed_str("<a/><!--c--><b/>");
+        let v: Vec<_> = p.map(|x| x.unwrap()).collect();
+        assert_eq!(v.len(), 9);
+        assert_eq!(v[4], Event::StartDocument);
+        assert_eq!(v[5], Event::Comment("c".to_owned()));
+        assert_eq!(v[8], Event::EndDocument);
+
+        // A stray end tag ends a document without removing the standard namespaces
+        let mut p = Parser::new();
+        p.set_multiple_documents(true);
+        p.feed_str("</a><b/>");
+        let v: Vec<_> = p.map(|x| x.unwrap()).collect();
+        assert_eq!(v.len(), 7);
+        assert_eq!(v[2], Event::EndDocument);
+        assert_eq!(v[6], Event::EndDocument);
+    }
+
+    #[test]
+    fn test_next_with_budget() {
+        let input = format!("<a>{}</a><b/>", "x".repeat(1000));
+        let mut p = Parser::new();
+        p.feed_str(&input);
+        let expected: Vec<_> = p.collect();
+
+        let mut p = Parser::new();
+        p.feed_str(&input);
+        let mut events = Vec::new();
+        let mut yields = 0;
+        loop {
+            match p.next_with_budget(100) {
+                ParseStatus::Event(event) => events.push(event),
+                ParseStatus::Yielded => yields += 1,
+                ParseStatus::NeedData => break,
+                status => panic!("Unexpected status {:?}", status)
+            }
+        }
+        assert_eq!(events, expected);
+        assert_eq!(yields, input.len() / 100);
+
+        let mut p = Parser::new();
+        p.feed_str("<a><1/>");
+        assert!(matches!(p.next_with_budget(10), ParseStatus::Event(Ok(_))));
+        assert!(matches!(p.next_with_budget(10), ParseStatus::Event(Err(_))));
+        assert_eq!(p.next_with_budget(10), ParseStatus::Failed);
+        p.feed_str("</a>");
+        assert_eq!(p.next_with_budget(10), ParseStatus::Failed);
+
+        let mut p = Parser::new();
+        p.set_stop_at_root_end(true);
+        p.feed_str("<a/><b/>");
+        assert!(matches!(p.next_with_budget(10), ParseStatus::Event(Ok(_))));
+        assert!(matches!(p.next_with_budget(10), ParseStatus::Event(Ok(_))));
+        assert_eq!(p.next_with_budget(10), ParseStatus::Finished);
+        p.reset(true);
+        assert!(matches!(p.next_with_budget(10), ParseStatus::Event(Ok(_))));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut p = Parser::new();
+        let handle = p.cancel_handle();
+        p.feed_str("<a><b/></a>");
+        assert!(matches!(p.next_with_budget(10), ParseStatus::Event(Ok(_))));
+        handle.cancel();
+        assert_eq!(p.next_with_budget(10), ParseStatus::Cancelled);
+        assert_eq!((&mut p).next(), None);
+
+        p.reset(true);
+        assert!(!handle.is_cancelled());
+        assert_eq!(p.count(), 3);
+    }
+
+    #[test]
+    fn test_checkpoint_states() {
+        for (i, st) in super::STATES.iter().enumerate() {
+            assert_eq!(super::STATES.iter().position(|x| x == st), Some(i));
+        }
+
+        let input = "<?pi x?><!DOCTYPE a><a x='1' y=\"2\"><b/><![CDATA[c]]><!--d-->e</a>";
+        for n in 0..input.len() {
+            let mut p = Parser::new();
+            p.feed_str(&input[..n]);
+            let _ = (&mut p).count();
+            if let Some(checkpoint) = p.checkpoint() {
+                assert_eq!(Parser::from_checkpoint(&checkpoint).unwrap().st, p.st);
+            }
+        }
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut p = Parser::new();
+        p.feed_str("<a><b/></a>");
+        assert_eq!((&mut p).count(), 4);
+        assert_eq!(p.finish(), Ok(()));
+
+        for input in &["<a><b/>", "<a/><b", "<a/><!-- c -"] {
+            let mut p = Parser::new();
+            p.feed_str(input);
+            assert!((&mut p).all(|x| x.is_ok()));
+            assert!(matches!(p.finish(), Err(ParserError { msg: "Unexpected end of input", .. })));
+        }
+
+        let mut p = Parser::new();
+        p.feed_bytes(b"<a/>\xe2").unwrap();
+        assert_eq!((&mut p).count(), 2);
+        assert!(p.finish().is_err());
+
+        let mut p = Parser::new();
+        p.set_stop_at_root_end(true);
+        p.feed_str("<a/><b");
+        assert_eq!((&mut p).count(), 2);
+        assert_eq!(p.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_input_capacity() {
+        let mut p = Parser::new();
+        p.set_input_capacity(Some(4));
+        assert_eq!(p.free_capacity(), 4);
+        assert_eq!(p.try_feed_str("<a>\u{e9}</a>"), 5);
+        assert_eq!(p.free_capacity(), 0);
+        assert_eq!(p.try_feed_str("</a>"), 0);
+        assert_eq!((&mut p).next(), Some(Ok(Event::ElementStart(StartTag {
+            name: "a".to_owned(),
+            ns: None,
+            prefix: None,
+            attributes: Attributes::new()
+        }))));
+        assert_eq!(p.free_capacity(), 3);
+
+        assert_eq!(p.feed_bytes(b"</a>\xe2"), Ok(3));
+        assert_eq!(p.free_capacity(), 0);
+        let _ = (&mut p).next();
+        assert_eq!(p.feed_bytes(b"\xe2\x82\xac"), Ok(3));
+        assert_eq!(p.take_remaining(), "/a\u{20ac}");
+
+        // The start of a multi-byte sequence is kept until its end is fed
+        let mut p = Parser::new();
+        p.set_input_capacity(Some(1));
+        assert_eq!(p.feed_bytes(b"\xe2\x82"), Ok(2));
+        p.feed_str("x");
+        assert_eq!(p.feed_bytes(b"\xac"), Ok(0));
+        assert_eq!(p.take_remaining(), "x");
+        assert_eq!(p.feed_bytes(b"\xe2\x82\xacy"), Ok(3));
+        let mut p = Parser::new();
+        p.set_input_capacity(Some(2));
+        assert_eq!(p.feed_bytes(b"x\xe2\x82"), Ok(3));
+        assert_eq!(p.free_capacity(), 0);
+        assert_eq!(p.feed_bytes(b"\xac"), Ok(1));
+        assert_eq!(p.take_remaining(), "x\u{20ac}");
+
+        // Undecodable data following the root element takes up capacity
+        let mut p = Parser::new();
+        p.set_input_capacity(Some(16));
+        p.set_stop_at_root_end(true);
+        assert_eq!(p.feed_bytes(b"<a/>"), Ok(4));
+        assert_eq!(p.feed_bytes(b"\xff"), Ok(1));
+        assert_eq!(p.free_capacity(), 11);
+        assert_eq!(p.feed_bytes(&[0; 100000]), Ok(11));
+        assert_eq!(p.feed_bytes(&[0; 100000]), Ok(0));
+        assert_eq!(p.free_capacity(), 0);
+        assert_eq!(p.count(), 2);
+        assert_eq!(p.take_remaining_bytes().len(), 12);
+        assert_eq!(p.free_capacity(), 16);
+        assert_eq!(p.feed_bytes(b"<a/>\xff\xfe"), Ok(6));
+        assert_eq!(p.feed_bytes(&[0xff; 20]), Ok(10));
+    }
 }
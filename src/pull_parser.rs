@@ -0,0 +1,350 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use super::{Event, Element, StartTag};
+use element_builder::{BuilderError, NamespaceContext};
+use parser::{Parser, ParserError};
+use std::error::Error;
+use std::fmt;
+
+#[derive(PartialEq, Debug)]
+/// The structure returned for errors encountered by a `PullParser`
+pub enum PullError {
+    /// Errors encountered by the `Parser`
+    Parser(ParserError),
+    /// Errors encountered while building an `Element`
+    Builder(BuilderError),
+    /// An event other than the expected one was found
+    UnexpectedEvent(Event),
+    /// The input ended before the expected event was found
+    UnexpectedEnd
+}
+
+impl Error for PullError {
+    fn description(&self) -> &str {
+        match *self {
+            PullError::Parser(ref err) => err.msg,
+            PullError::Builder(_) => "Failed to build element",
+            PullError::UnexpectedEvent(_) => "Unexpected event",
+            PullError::UnexpectedEnd => "Unexpected end of input"
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            PullError::Parser(ref err) => Some(err),
+            PullError::Builder(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for PullError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PullError::Parser(ref err) => err.fmt(f),
+            PullError::Builder(ref err) => err.fmt(f),
+            PullError::UnexpectedEvent(ref event) => write!(f, "Unexpected event: {:?}", event),
+            PullError::UnexpectedEnd => write!(f, "Unexpected end of input")
+        }
+    }
+}
+
+impl From<ParserError> for PullError {
+    fn from(err: ParserError) -> PullError { PullError::Parser(err) }
+}
+
+impl From<BuilderError> for PullError {
+    fn from(err: BuilderError) -> PullError { PullError::Builder(err) }
+}
+
+/// A pull parser, wrapping a `Parser` to support writing recursive-descent readers
+///
+/// The complete document is expected to be fed to the `Parser` before reading,
+/// running out of input is reported as `PullError::UnexpectedEnd`. Iterating over
+/// the `PullParser` returns the remaining events, followed by `PullError::UnexpectedEnd`
+/// if the document is incomplete.
+///
+/// ~~~
+/// use xml::{Parser, PullParser};
+///
+/// let mut p = Parser::new();
+/// p.feed_str("<config><name>demo</name><extra><x/></extra><port>80</port></config>");
+///
+/// let mut pull = PullParser::new(p);
+/// pull.expect_start("config", None).unwrap();
+/// pull.expect_start("name", None).unwrap();
+/// assert_eq!(pull.read_text().unwrap(), "demo");
+/// pull.expect_start("extra", None).unwrap();
+/// pull.skip_element().unwrap();
+/// let port = pull.read_element().unwrap();
+/// assert_eq!(port.content_str(), "80");
+/// ~~~
+pub struct PullParser {
+    parser: Parser,
+    peeked: Option<Option<Result<Event, ParserError>>>,
+    context: NamespaceContext,
+    // Whether iterating reported the incomplete document already
+    end_reported: bool
+}
+
+impl PullParser {
+    /// Returns a new `PullParser` reading events from `parser`
+    pub fn new(parser: Parser) -> PullParser {
+        PullParser {
+            parser,
+            peeked: None,
+            context: NamespaceContext::default(),
+            end_reported: false
+        }
+    }
+
+    /// Returns a mutable reference to the wrapped `Parser`, e.g. to feed it more data
+    pub fn get_mut(&mut self) -> &mut Parser {
+        &mut self.parser
+    }
+
+    /// Returns the wrapped `Parser`
+    pub fn into_inner(self) -> Parser {
+        self.parser
+    }
+
+    /// Returns the next event without consuming it
+    pub fn peek(&mut self) -> Option<&Result<Event, ParserError>> {
+        if self.peeked.is_none() {
+            self.peeked = Some((&mut self.parser).next());
+        }
+        match self.peeked {
+            Some(ref event) => event.as_ref(),
+            None => unreachable!()
+        }
+    }
+
+    // Consume the next event, tracking namespace declarations
+    fn next_event(&mut self) -> Result<Event, PullError> {
+        let event = match self.peeked.take() {
+            Some(event) => event,
+            None => (&mut self.parser).next()
+        };
+        let event = match event {
+            Some(event) => event?,
+            None => return Err(PullError::UnexpectedEnd)
+        };
+        self.context.handle_event(&event);
+        Ok(event)
+    }
+
+    // Consume whitespace, comments and processing instructions
+    fn skip_insignificant(&mut self) -> Result<(), PullError> {
+        loop {
+            let skip = match self.peek() {
+                Some(&Ok(Event::Characters(ref text))) => text.trim().is_empty(),
+                Some(&Ok(Event::Whitespace(_)))
+                | Some(&Ok(Event::Comment(_)))
+                | Some(&Ok(Event::PI(_))) => true,
+                _ => false
+            };
+            if !skip {
+                return Ok(());
+            }
+            self.next_event()?;
+        }
+    }
+
+    /// Consumes the next `ElementStart`, which has to have the given name and namespace.
+    ///
+    /// Whitespace, comments and processing instructions before it are skipped.
+    pub fn expect_start(&mut self, name: &str, ns: Option<&str>) -> Result<StartTag, PullError> {
+        loop {
+            self.skip_insignificant()?;
+            match self.next_event()? {
+                Event::StartPrefixMapping { .. } => continue,
+                Event::ElementStart(tag) => {
+                    if tag.name == name && tag.ns.as_ref().map(|x| &x[..]) == ns {
+                        return Ok(tag);
+                    }
+                    return Err(PullError::UnexpectedEvent(Event::ElementStart(tag)));
+                }
+                event => return Err(PullError::UnexpectedEvent(event))
+            }
+        }
+    }
+
+    /// Skips the rest of the element whose `ElementStart` was consumed last,
+    /// including its `ElementEnd`. No `Element` is built for the skipped content,
+    /// while its events are still parsed, with their names and attributes, and dropped.
+    pub fn skip_element(&mut self) -> Result<(), PullError> {
+        let mut depth = 1usize;
+        loop {
+            match self.next_event()? {
+                Event::ElementStart(_) => depth += 1,
+                Event::ElementEnd(_) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => ()
+            }
+        }
+    }
+
+    /// Reads the character data up to the end of the element whose `ElementStart` was consumed
+    /// last, including that of child elements. The `ElementEnd` is consumed as well.
+    pub fn read_text(&mut self) -> Result<String, PullError> {
+        let mut text = String::new();
+        let mut depth = 1usize;
+        loop {
+            match self.next_event()? {
+                Event::Characters(data)
+                | Event::CDATA(data)
+                | Event::Whitespace(data) => text.push_str(&data),
+                Event::ElementStart(_) => depth += 1,
+                Event::ElementEnd(_) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(text);
+                    }
+                }
+                _ => ()
+            }
+        }
+    }
+
+    /// Reads the element whose `ElementStart` is the next event into an `Element`.
+    ///
+    /// Whitespace, comments and processing instructions before it are skipped.
+    /// Namespaces declared by enclosing elements are taken into account.
+    pub fn read_element(&mut self) -> Result<Element, PullError> {
+        self.skip_insignificant()?;
+        match self.peek() {
+            Some(&Ok(Event::ElementStart(_)))
+            | Some(&Ok(Event::StartPrefixMapping { .. })) => (),
+            _ => {
+                let event = self.next_event()?;
+                return Err(PullError::UnexpectedEvent(event));
+            }
+        }
+
+        let mut builder = self.parser.element_builder();
+        self.context.prepare(&mut builder);
+        loop {
+            let event = self.next_event()?;
+            if let Some(result) = builder.handle_event(Ok(event)) {
+                return result.map_err(From::from);
+            }
+        }
+    }
+}
+
+impl Iterator for PullParser {
+    type Item = Result<Event, PullError>;
+
+    fn next(&mut self) -> Option<Result<Event, PullError>> {
+        match self.next_event() {
+            Ok(event) => {
+                self.end_reported = false;
+                Some(Ok(event))
+            }
+            Err(PullError::UnexpectedEnd) => {
+                if self.end_reported || self.parser.finish().is_ok() {
+                    return None;
+                }
+                self.end_reported = true;
+                Some(Err(PullError::UnexpectedEnd))
+            }
+            Err(err) => Some(Err(err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PullError, PullParser};
+    use parser::Parser;
+    use Event;
+
+    fn pull(data: &str) -> PullParser {
+        let mut p = Parser::new();
+        p.feed_str(data);
+        PullParser::new(p)
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut p = pull("<a>x</a>");
+        assert!(matches!(p.peek(), Some(&Ok(Event::ElementStart(_)))));
+        assert!(matches!(p.next(), Some(Ok(Event::ElementStart(_)))));
+        assert_eq!(p.peek(), Some(&Ok(Event::Characters("x".to_owned()))));
+    }
+
+    #[test]
+    fn test_iterator() {
+        let p = pull("<a><b/></a>");
+        assert_eq!(p.filter(|x| x.is_ok()).count(), 4);
+
+        let mut p = pull("<a><b/>");
+        assert!(matches!(p.next(), Some(Ok(Event::ElementStart(_)))));
+        assert_eq!(p.nth(2), Some(Err(PullError::UnexpectedEnd)));
+        assert_eq!(p.next(), None);
+        p.get_mut().feed_str("</a>");
+        assert!(matches!(p.next(), Some(Ok(Event::ElementEnd(_)))));
+        assert_eq!(p.next(), None);
+
+        let mut p = pull("<a><1/></a>");
+        assert!(matches!(p.nth(1), Some(Err(PullError::Parser(_)))));
+        assert_eq!(p.next(), None);
+    }
+
+    #[test]
+    fn test_expect_start() {
+        let mut p = pull("<?pi?>\n<!--c--> <a xmlns='urn:a'><b/></a>");
+        let tag = p.expect_start("a", Some("urn:a")).unwrap();
+        assert_eq!(tag.name, "a");
+        match p.expect_start("c", Some("urn:a")) {
+            Err(PullError::UnexpectedEvent(Event::ElementStart(tag))) => assert_eq!(tag.name, "b"),
+            e => panic!("Unexpected result: {:?}", e)
+        }
+    }
+
+    #[test]
+    fn test_skip_element() {
+        let mut p = pull("<a><b><b/>x</b><c/><d><e/></d>y</a>");
+        p.expect_start("a", None).unwrap();
+        p.expect_start("b", None).unwrap();
+        p.skip_element().unwrap();
+        p.expect_start("c", None).unwrap();
+        p.skip_element().unwrap();
+        p.expect_start("d", None).unwrap();
+        p.skip_element().unwrap();
+        assert_eq!(p.read_text().unwrap(), "y");
+        assert_eq!(p.next(), None);
+    }
+
+    #[test]
+    fn test_read_text() {
+        let mut p = pull("<a>x<![CDATA[<y>]]><b>z</b>&amp;</a><c/>");
+        p.expect_start("a", None).unwrap();
+        assert_eq!(p.read_text().unwrap(), "x<y>z&");
+        p.expect_start("c", None).unwrap();
+        assert_eq!(p.read_text(), Ok(String::new()));
+        assert_eq!(p.read_text(), Err(PullError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_read_element() {
+        let mut p = pull("<feed xmlns='urn:atom' xmlns:m='urn:m'>\
+                          <entry><m:thumb/></entry><entry/></feed>");
+        p.expect_start("feed", Some("urn:atom")).unwrap();
+        let entry = p.read_element().unwrap();
+        assert_eq!(format!("{}", entry), "<entry xmlns='urn:atom'><m:thumb/></entry>");
+        assert_eq!(entry.get_child("thumb", Some("urn:m")).map(|x| &x.name[..]), Some("thumb"));
+        let entry = p.read_element().unwrap();
+        assert_eq!(entry.ns, Some("urn:atom".to_owned()));
+        assert!(matches!(p.read_element(),
+                         Err(PullError::UnexpectedEvent(Event::ElementEnd(_)))));
+    }
+}
@@ -0,0 +1,205 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use super::{StartTag, EndTag};
+use parser::ParserError;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// The position of the `Parser` in its input
+pub struct Locator {
+    /// The line number of the last character consumed
+    pub line: u32,
+    /// The column number of the last character consumed
+    pub col: u32
+}
+
+/// Callbacks for the content of a document, as called by `Parser::parse_with()`
+///
+/// All methods default to doing nothing.
+///
+/// ~~~
+/// use xml::{ContentHandler, ErrorHandler, Parser, StartTag};
+///
+/// struct Counter(usize);
+///
+/// impl ContentHandler for Counter {
+///     fn start_element(&mut self, _tag: &StartTag) {
+///         self.0 += 1;
+///     }
+/// }
+///
+/// impl ErrorHandler for Counter {}
+///
+/// let mut p = Parser::new();
+/// p.feed_str("<a><b/><c/></a>");
+/// let mut counter = Counter(0);
+/// p.finish_with(&mut counter).unwrap();
+/// assert_eq!(counter.0, 3);
+/// ~~~
+pub trait ContentHandler {
+    /// Called with the current position before each of the other callbacks
+    fn set_document_locator(&mut self, _locator: Locator) {}
+
+    /// Called when a document starts, if enabled by `Parser::set_multiple_documents()`
+    fn start_document(&mut self) {}
+
+    /// Called when a document ends, if enabled by `Parser::set_multiple_documents()`
+    fn end_document(&mut self) {}
+
+    /// Called for a start tag
+    fn start_element(&mut self, _tag: &StartTag) {}
+
+    /// Called for an end tag, or after `start_element()` for an empty-element tag
+    fn end_element(&mut self, _tag: &EndTag) {}
+
+    /// Called for character data
+    fn characters(&mut self, _text: &str) {}
+
+    /// Called for a CDATA section, defaults to calling `characters()`
+    fn cdata(&mut self, text: &str) {
+        self.characters(text);
+    }
+
+    /// Called for whitespace reported by `WhitespaceMode::Report`
+    fn ignorable_whitespace(&mut self, _text: &str) {}
+
+    /// Called for a processing instruction, with the target split from the data following it
+    fn processing_instruction(&mut self, _target: &str, _data: &str) {}
+
+    /// Called for a comment
+    fn comment(&mut self, _text: &str) {}
+
+    /// Called for an entity reference preserved by `Parser::set_preserve_entity_refs()`
+    fn skipped_entity(&mut self, _name: &str) {}
+
+    /// Called when a namespace prefix comes into scope,
+    /// if enabled by `Parser::set_report_prefix_mappings()`
+    fn start_prefix_mapping(&mut self, _prefix: Option<&str>, _uri: &str) {}
+
+    /// Called when a namespace prefix goes out of scope
+    fn end_prefix_mapping(&mut self, _prefix: Option<&str>) {}
+}
+
+/// Callbacks for problems found while parsing, as called by `Parser::parse_with()`
+///
+/// All methods default to doing nothing.
+pub trait ErrorHandler {
+    /// Called for problems repaired in lenient mode, see `Parser::warnings()`
+    fn warning(&mut self, _err: &ParserError) {}
+
+    /// Called for errors parsing can continue after, e.g. the input ending while
+    /// elements are still open, as reported by `Parser::finish_with()`
+    fn error(&mut self, _err: &ParserError) {}
+
+    /// Called for the error parsing stopped at
+    fn fatal_error(&mut self, _err: &ParserError) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContentHandler, ErrorHandler, Locator};
+    use parser::{Parser, ParserError};
+    use {StartTag, EndTag};
+
+    #[derive(Default)]
+    struct Recorder {
+        locator: Option<Locator>,
+        calls: Vec<String>
+    }
+
+    impl ContentHandler for Recorder {
+        fn set_document_locator(&mut self, locator: Locator) {
+            self.locator = Some(locator);
+        }
+
+        fn start_element(&mut self, tag: &StartTag) {
+            let locator = self.locator.unwrap();
+            self.calls.push(format!("start {} {}:{}", tag.name, locator.line, locator.col));
+        }
+
+        fn end_element(&mut self, tag: &EndTag) {
+            self.calls.push(format!("end {}", tag.name));
+        }
+
+        fn characters(&mut self, text: &str) {
+            self.calls.push(format!("chars {}", text));
+        }
+
+        fn processing_instruction(&mut self, target: &str, data: &str) {
+            self.calls.push(format!("pi {}|{}", target, data));
+        }
+    }
+
+    impl ErrorHandler for Recorder {
+        fn warning(&mut self, err: &ParserError) {
+            self.calls.push(format!("warning {}", err.msg));
+        }
+
+        fn error(&mut self, err: &ParserError) {
+            self.calls.push(format!("error {}", err.msg));
+        }
+
+        fn fatal_error(&mut self, err: &ParserError) {
+            self.calls.push(format!("fatal {}", err.msg));
+        }
+    }
+
+    #[test]
+    fn test_parse_with() {
+        let mut p = Parser::new();
+        p.feed_str("<?target  some data?>\n<a>x<![CDATA[y]]><!--c--><b/></a>");
+        let mut handler = Recorder::default();
+        assert_eq!(p.parse_with(&mut handler), Ok(()));
+        assert_eq!(handler.calls, vec![
+            "pi target|some data",
+            "chars \n",
+            "start a 2:3",
+            "chars x",
+            "chars y",
+            "start b 2:28",
+            "end b",
+            "end a"
+        ]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut p = Parser::new();
+        p.set_lenient(true);
+        p.feed_str("<a>&bogus;<b>");
+        let mut handler = Recorder::default();
+        assert_eq!(p.parse_with(&mut handler), Ok(()));
+        assert_eq!(handler.calls, vec![
+            "start a 1:3",
+            "warning Found invalid entity",
+            "chars &bogus;",
+            "start b 1:13"
+        ]);
+
+        let mut handler = Recorder::default();
+        p.feed_str("</b></c>");
+        assert_eq!(p.parse_with(&mut handler), Ok(()));
+        assert_eq!(handler.calls,
+                   vec!["end b", "warning Ignored end tag without matching start tag"]);
+
+        let mut handler = Recorder::default();
+        p.feed_str("x");
+        assert_eq!(p.finish_with(&mut handler), Ok(()));
+        assert_eq!(handler.calls, vec!["error Unexpected end of input"]);
+
+        let mut p = Parser::new();
+        p.feed_str("<a>x</a>");
+        let mut handler = Recorder::default();
+        assert_eq!(p.finish_with(&mut handler), Ok(()));
+        assert_eq!(handler.calls, vec!["start a 1:3", "chars x", "end a"]);
+
+        let mut p = Parser::new();
+        p.feed_str("<a><1/></a>");
+        let mut handler = Recorder::default();
+        assert!(p.parse_with(&mut handler).is_err());
+        assert_eq!(handler.calls, vec!["start a 1:3", "fatal Invalid name"]);
+    }
+}
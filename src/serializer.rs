@@ -0,0 +1,346 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use super::Xml;
+use element::{bound_prefix, write_node, Element};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use writer::{write_escaped, CountingWriter};
+
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+// The position of an element in the output
+#[derive(Clone, Copy)]
+struct Level {
+    depth: usize,
+    // Whether the element is placed on a line of its own
+    indent: bool,
+    // Whether whitespace is preserved by `xml:space`
+    preserve: bool
+}
+
+/// A configurable serializer for `Element`s, e.g. to pretty-print or minify them
+///
+/// By default the output is the same as that of the `Display` implementation.
+///
+/// When indenting, elements containing only elements, comments and processing
+/// instructions besides whitespace have each child placed on a line of its own,
+/// replacing that whitespace. Elements with other content, such as text, are written
+/// as they are, as are elements within the scope of `xml:space='preserve'`.
+///
+/// ~~~
+/// use xml::{Element, Serializer};
+///
+/// let elem: Element = "<config><!-- defaults --><port>80</port>\n<host  name='a'/></config>"
+///                     .parse().unwrap();
+///
+/// let mut serializer = Serializer::new();
+/// serializer.set_indent(Some("  "));
+/// serializer.set_double_quotes(true);
+/// assert_eq!(serializer.serialize(&elem).unwrap(),
+///            "<config>\n  <!-- defaults -->\n  <port>80</port>\n  <host name=\"a\"/>\n</config>");
+///
+/// let mut serializer = Serializer::new();
+/// serializer.set_minify(true);
+/// assert_eq!(serializer.serialize(&elem).unwrap(),
+///            "<config><port>80</port><host name='a'/></config>");
+/// ~~~
+#[derive(Clone, Debug)]
+pub struct Serializer {
+    indent: Option<String>,
+    max_width: Option<usize>,
+    wrap_attributes: bool,
+    double_quotes: bool,
+    self_closing: bool,
+    minify: bool
+}
+
+impl Serializer {
+    /// Returns a new `Serializer`, producing the same output as `Display`
+    pub fn new() -> Serializer {
+        Serializer {
+            indent: None,
+            max_width: None,
+            wrap_attributes: false,
+            double_quotes: false,
+            self_closing: true,
+            minify: false
+        }
+    }
+
+    /// Sets the string to indent nested elements with, one per level,
+    /// `None` to write everything on one line.
+    pub fn set_indent(&mut self, indent: Option<&str>) {
+        self.indent = indent.map(|x| x.to_owned());
+    }
+
+    /// Sets the width start tags may have when indenting before their attributes
+    /// are placed on lines of their own, `None` to not limit it.
+    /// The width is estimated from the unescaped attribute values.
+    pub fn set_max_width(&mut self, max_width: Option<usize>) {
+        self.max_width = max_width;
+    }
+
+    /// Whether to place each attribute on a line of its own when indenting,
+    /// if an element has several.
+    pub fn set_wrap_attributes(&mut self, wrap_attributes: bool) {
+        self.wrap_attributes = wrap_attributes;
+    }
+
+    /// Whether to quote attribute values with `"` instead of `'`
+    pub fn set_double_quotes(&mut self, double_quotes: bool) {
+        self.double_quotes = double_quotes;
+    }
+
+    /// Whether to write elements without content as `<a/>` instead of `<a></a>`
+    pub fn set_self_closing(&mut self, self_closing: bool) {
+        self.self_closing = self_closing;
+    }
+
+    /// Whether to remove comments and whitespace-only text,
+    /// except within the scope of `xml:space='preserve'`.
+    pub fn set_minify(&mut self, minify: bool) {
+        self.minify = minify;
+    }
+
+    /// Writes `elem` to `w`, returning the number of bytes written.
+    ///
+    /// Writing fails with an `io::ErrorKind::InvalidData` error if no prefix is bound
+    /// for a namespace used, or if the element contains characters XML 1.0 does not allow.
+    pub fn write_to<W: Write + ?Sized>(&self, elem: &Element, w: &mut W) -> io::Result<usize> {
+        let mut w = CountingWriter::new(w);
+        let level = Level { depth: 0, indent: self.indent.is_some(), preserve: false };
+        self.write_elem(elem, None, &mut Vec::new(), level, &mut w)?;
+        Ok(w.count)
+    }
+
+    /// Returns `elem` serialized as a `String`, see `write_to()`
+    pub fn serialize(&self, elem: &Element) -> io::Result<String> {
+        let mut out = Vec::new();
+        self.write_to(elem, &mut out)?;
+        Ok(String::from_utf8(out).expect("Serialized XML is valid UTF-8"))
+    }
+
+    fn newline<W: Write + ?Sized>(&self, depth: usize, w: &mut W) -> io::Result<()> {
+        w.write_all(b"\n")?;
+        let indent = self.indent.as_deref().unwrap_or("");
+        for _ in 0..depth {
+            w.write_all(indent.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Whether a child node is removed from the output
+    fn skipped(&self, node: &Xml, preserve: bool) -> bool {
+        self.minify && match *node {
+            Xml::CommentNode(_) => !preserve,
+            Xml::CharacterNode(ref text) => !preserve && is_whitespace(text),
+            _ => false
+        }
+    }
+
+    fn write_elem<'a, W: Write + ?Sized>(&self, elem: &'a Element, parent: Option<&Element>,
+                                         scopes: &mut Vec<&'a HashMap<String, String>>,
+                                         level: Level, w: &mut W) -> io::Result<()> {
+        scopes.push(&elem.prefixes);
+        let Level { depth, indent, preserve } = level;
+        let preserve = match elem.attributes.get_by_name("space", Some(XML_NS)).map(|x| &x[..]) {
+            Some("preserve") => true,
+            Some("default") => false,
+            _ => preserve
+        };
+
+        let qname = if elem.ns != elem.default_ns {
+            format!("{}:{}", bound_prefix(scopes, elem.ns.as_deref())?, elem.name)
+        } else {
+            elem.name.clone()
+        };
+
+        // Declare the default namespace where Display would
+        let mut attributes = Vec::with_capacity(elem.attributes.len() + 1);
+        if !elem.attributes.iter().any(|((name, _), _)| name == "xmlns") {
+            match (parent, &elem.default_ns) {
+                (None, Some(ns)) => attributes.push(("xmlns".to_owned(), &ns[..])),
+                (Some(parent), ns) if parent.default_ns != *ns => {
+                    attributes.push(("xmlns".to_owned(), ns.as_deref().unwrap_or("")))
+                }
+                _ => ()
+            }
+        }
+        for ((name, ns), value) in &elem.attributes {
+            let name = match *ns {
+                Some(ref ns) => format!("{}:{}", bound_prefix(scopes, Some(ns))?, name),
+                None => name.clone()
+            };
+            attributes.push((name, &value[..]));
+        }
+
+        let indent_len = self.indent.as_ref().map_or(0, |x| x.len());
+        let width = indent_len * depth + qname.len() + 2
+                    + attributes.iter().map(|x| x.0.len() + x.1.len() + 4).sum::<usize>();
+        let wrap = indent && attributes.len() > 1
+                   && (self.wrap_attributes || self.max_width.is_some_and(|x| width > x));
+
+        let quote: &[u8] = if self.double_quotes { b"\"" } else { b"'" };
+        write!(w, "<{}", qname)?;
+        for (name, value) in &attributes {
+            if wrap {
+                self.newline(depth + 1, w)?;
+            } else {
+                w.write_all(b" ")?;
+            }
+            write!(w, "{}=", name)?;
+            w.write_all(quote)?;
+            write_escaped(w, value)?;
+            w.write_all(quote)?;
+        }
+
+        let children: Vec<&Xml> = elem.children.iter()
+                                      .filter(|x| !self.skipped(x, preserve))
+                                      .collect();
+        if children.is_empty() {
+            if self.self_closing {
+                w.write_all(b"/>")?;
+            } else {
+                write!(w, "></{}>", qname)?;
+            }
+            scopes.pop();
+            return Ok(());
+        }
+
+        // Only indent children if there is no significant text between them
+        let indent_children = indent && !preserve
+            && children.iter().any(|x| !matches!(**x, Xml::CharacterNode(_)))
+            && children.iter().all(|x| match **x {
+                Xml::CharacterNode(ref text) => is_whitespace(text),
+                Xml::CDATANode(_) | Xml::EntityRefNode(_) => false,
+                _ => true
+            });
+
+        w.write_all(b">")?;
+        for child in children {
+            if indent_children {
+                if let Xml::CharacterNode(_) = *child {
+                    continue;
+                }
+                self.newline(depth + 1, w)?;
+            }
+            match *child {
+                Xml::ElementNode(ref child) => {
+                    let level = Level { depth: depth + 1, indent: indent_children, preserve };
+                    self.write_elem(child, Some(elem), scopes, level, w)?
+                }
+                ref o => write_node(o, w)?
+            }
+        }
+        if indent_children {
+            self.newline(depth, w)?;
+        }
+        write!(w, "</{}>", qname)?;
+        scopes.pop();
+        Ok(())
+    }
+}
+
+impl Default for Serializer {
+    fn default() -> Serializer {
+        Serializer::new()
+    }
+}
+
+fn is_whitespace(text: &str) -> bool {
+    text.chars().all(|c| matches!(c, ' ' | '\t' | '\r' | '\n'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Serializer;
+    use Element;
+
+    fn pretty() -> Serializer {
+        let mut s = Serializer::new();
+        s.set_indent(Some("  "));
+        s
+    }
+
+    #[test]
+    fn test_default() {
+        let inputs = [
+            "<a xmlns='urn:a' xmlns:b='urn:b'><b:c x='&apos;1&apos;' b:y='&amp;'>t &lt; \
+             <![CDATA[<d>]]><!--c--><?pi x?></b:c><e xmlns=''/></a>",
+            "<x:a xmlns:x='urn:x' xml:lang='en'><b><x:c/></b>\u{e9}</x:a>"
+        ];
+        for input in &inputs {
+            let elem: Element = input.parse().unwrap();
+            assert_eq!(Serializer::new().serialize(&elem).unwrap(), elem.to_string());
+        }
+    }
+
+    #[test]
+    fn test_indent() {
+        let elem: Element = "<a>\n<b><c/><?pi?></b>  <d>x <e/> y</d><f></f></a>".parse().unwrap();
+        assert_eq!(pretty().serialize(&elem).unwrap(),
+                   "<a>\n  <b>\n    <c/>\n    <?pi?>\n  </b>\n  <d>x <e/> y</d>\n  <f/>\n</a>");
+
+        let mut s = pretty();
+        s.set_indent(Some("\t"));
+        s.set_self_closing(false);
+        assert_eq!(s.serialize(&elem).unwrap(),
+                   "<a>\n\t<b>\n\t\t<c></c>\n\t\t<?pi?>\n\t</b>\n\t<d>x <e></e> y</d>\n\t<f></f>\n</a>");
+
+        let reparsed: Element = pretty().serialize(&elem).unwrap().parse().unwrap();
+        assert_eq!(reparsed.get_child("d", None), elem.get_child("d", None));
+    }
+
+    #[test]
+    fn test_preserve() {
+        let elem: Element = "<a><b xml:space='preserve'> <c> <d/> </c> </b>\
+                             <e><f xml:space='default'><g/></f></e></a>".parse().unwrap();
+        assert_eq!(pretty().serialize(&elem).unwrap(),
+                   "<a>\n  <b xml:space='preserve'> <c> <d/> </c> </b>\n  \
+                    <e>\n    <f xml:space='default'>\n      <g/>\n    </f>\n  </e>\n</a>");
+
+        let mut s = Serializer::new();
+        s.set_minify(true);
+        assert_eq!(s.serialize(&elem).unwrap(),
+                   "<a><b xml:space='preserve'> <c> <d/> </c> </b>\
+                    <e><f xml:space='default'><g/></f></e></a>");
+    }
+
+    #[test]
+    fn test_attributes() {
+        let elem: Element = "<a><b x='1' y='&quot;2&quot;'/><c z='3'/></a>".parse().unwrap();
+        let mut s = pretty();
+        s.set_wrap_attributes(true);
+        s.set_double_quotes(true);
+        assert_eq!(s.serialize(&elem).unwrap(),
+                   "<a>\n  <b\n    x=\"1\"\n    y=\"&quot;2&quot;\"/>\n  <c z=\"3\"/>\n</a>");
+
+        let mut s = pretty();
+        s.set_max_width(Some(20));
+        assert_eq!(s.serialize(&elem).unwrap(),
+                   "<a>\n  <b x='1' y='&quot;2&quot;'/>\n  <c z='3'/>\n</a>");
+        s.set_max_width(Some(10));
+        assert_eq!(s.serialize(&elem).unwrap(),
+                   "<a>\n  <b\n    x='1'\n    y='&quot;2&quot;'/>\n  <c z='3'/>\n</a>");
+    }
+
+    #[test]
+    fn test_minify() {
+        let elem: Element = "<a>\n  <!-- c -->\n  <b> x </b>\n  <c>\n  </c>\n</a>".parse().unwrap();
+        let mut s = Serializer::new();
+        s.set_minify(true);
+        assert_eq!(s.serialize(&elem).unwrap(), "<a><b> x </b><c/></a>");
+
+        let mut out = Vec::new();
+        assert_eq!(s.write_to(&elem, &mut out).unwrap(), 21);
+
+        let elem: Element = "<a><b xml:space='preserve'><!-- keep --> </b><!-- c --></a>"
+                            .parse().unwrap();
+        assert_eq!(s.serialize(&elem).unwrap(),
+                   "<a><b xml:space='preserve'><!-- keep --> </b></a>");
+    }
+}
@@ -0,0 +1,163 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use super::Event;
+use element::ElementEvents;
+use element_builder::BuilderError;
+use parser::{Parser, ParserError};
+use sax::Locator;
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+
+/// A source of `Event`s, such as a `Parser`
+///
+/// Every iterator over `Result<Event, E>` is an event source, e.g. a recorded list of
+/// events or a transformation of another source. A `Parser` is an event source
+/// reporting the position of its events, `Element::events()` replays an `Element`.
+///
+/// ~~~
+/// use xml::{ElementBuilder, Event, EventSource, Parser, ParserError};
+///
+/// fn count_elements<S: EventSource>(source: &mut S) -> Result<usize, S::Error> {
+///     let mut count = 0;
+///     while let Some(event) = source.next_event() {
+///         if let Event::ElementStart(_) = event? {
+///             count += 1;
+///         }
+///     }
+///     Ok(count)
+/// }
+///
+/// let mut p = Parser::new();
+/// p.feed_str("<a><b/></a>");
+/// let events: Vec<Result<Event, ParserError>> = p.collect();
+/// assert_eq!(count_elements(&mut events.into_iter()), Ok(2));
+/// ~~~
+pub trait EventSource {
+    /// The type of errors produced by the source
+    type Error;
+
+    /// Returns the next event, or `None` if no event is available
+    fn next_event(&mut self) -> Option<Result<Event, Self::Error>>;
+
+    /// Returns the position of the last event returned in the input, if known
+    fn position(&self) -> Option<Locator> {
+        None
+    }
+}
+
+impl<I, E> EventSource for I where I: Iterator<Item = Result<Event, E>> {
+    type Error = E;
+
+    fn next_event(&mut self) -> Option<Result<Event, E>> {
+        self.next()
+    }
+}
+
+impl EventSource for Parser {
+    type Error = ParserError;
+
+    fn next_event(&mut self) -> Option<Result<Event, ParserError>> {
+        (&mut *self).next()
+    }
+
+    fn position(&self) -> Option<Locator> {
+        Some(self.locator())
+    }
+}
+
+impl<'a> EventSource for ElementEvents<'a> {
+    type Error = Infallible;
+
+    fn next_event(&mut self) -> Option<Result<Event, Infallible>> {
+        self.next().map(Ok)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+/// The structure returned for errors encountered while processing the events of an `EventSource`
+pub enum SourceError<E> {
+    /// Errors produced by the `EventSource`
+    Source(E),
+    /// Errors encountered while building an `Element`
+    Builder(BuilderError)
+}
+
+impl<E: Error> Error for SourceError<E> {
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            SourceError::Source(ref err) => Some(err),
+            SourceError::Builder(ref err) => Some(err)
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for SourceError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourceError::Source(ref err) => err.fmt(f),
+            SourceError::Builder(ref err) => err.fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventSource, SourceError};
+    use element_builder::{BuilderError, ElementBuilder};
+    use parser::Parser;
+    use {Element, EndTag, Event};
+
+    #[test]
+    fn test_parser_source() {
+        let mut p = Parser::new();
+        p.feed_str("<a>\n<b/></a><c/>");
+        assert_eq!(p.position().map(|x| (x.line, x.col)), Some((1, 0)));
+
+        let mut e = ElementBuilder::new();
+        let elem = e.build_from(&mut p).unwrap().unwrap();
+        assert_eq!(elem, "<a>\n<b/></a>".parse::<Element>().unwrap());
+        assert_eq!(p.position().map(|x| (x.line, x.col)), Some((2, 8)));
+        assert_eq!(e.build_from(&mut p).unwrap().unwrap().name, "c");
+        assert!(e.build_from(&mut p).is_none());
+    }
+
+    #[test]
+    fn test_recorded_source() {
+        let mut p = Parser::new();
+        p.feed_str("<a><b>x</b></a>");
+        let events: Vec<_> = p.collect();
+        let mut source = events.into_iter();
+        assert_eq!(source.position(), None);
+
+        let mut e = ElementBuilder::new();
+        let elem = e.build_from(&mut source).unwrap().unwrap();
+        assert_eq!(elem, "<a><b>x</b></a>".parse().unwrap());
+        assert!(e.build_from(&mut source).is_none());
+    }
+
+    #[test]
+    fn test_transformed_source() {
+        let events = vec![
+            Ok(Event::ElementStart(super::super::StartTag {
+                name: "a".to_owned(),
+                ns: None,
+                prefix: None,
+                attributes: Default::default()
+            })),
+            Err("broken"),
+            Ok(Event::ElementEnd(EndTag { name: "b".to_owned(), ns: None, prefix: None }))
+        ];
+        let mut source = events.into_iter().filter(|x| x.is_ok());
+        let mut e = ElementBuilder::new();
+        assert_eq!(e.build_from(&mut source),
+                   Some(Err(SourceError::Builder(BuilderError::ImproperNesting))));
+
+        let mut source = vec![Err("broken")].into_iter();
+        assert_eq!(e.build_from(&mut source), Some(Err(SourceError::Source("broken"))));
+    }
+}
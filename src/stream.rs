@@ -0,0 +1,349 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use super::{Event, Element};
+use element_builder::{BuilderError, ElementBuilder};
+use futures_core::Stream;
+use parser::{Parser, ParserError};
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+#[derive(Debug)]
+/// The structure returned for errors encountered by an `EventStream` or `ElementStream`
+pub enum StreamError {
+    /// Errors encountered while reading the input
+    Io(io::Error),
+    /// Errors encountered by the `Parser`
+    Parser(ParserError),
+    /// Errors encountered while building an `Element`
+    Builder(BuilderError),
+    /// The parser's input buffer is full, while it needs more data to make progress
+    BufferFull
+}
+
+impl Error for StreamError {
+    fn description(&self) -> &str {
+        match *self {
+            StreamError::Io(_) => "Failed to read input",
+            StreamError::Parser(ref err) => err.msg,
+            StreamError::Builder(_) => "Failed to build element",
+            StreamError::BufferFull => "Input buffer full"
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            StreamError::Io(ref err) => Some(err),
+            StreamError::Parser(ref err) => Some(err),
+            StreamError::Builder(ref err) => Some(err),
+            StreamError::BufferFull => None
+        }
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::Io(ref err) => err.fmt(f),
+            StreamError::Parser(ref err) => err.fmt(f),
+            StreamError::Builder(ref err) => err.fmt(f),
+            StreamError::BufferFull => write!(f, "Input buffer full")
+        }
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(err: io::Error) -> StreamError { StreamError::Io(err) }
+}
+
+impl From<ParserError> for StreamError {
+    fn from(err: ParserError) -> StreamError { StreamError::Parser(err) }
+}
+
+/// A `Stream` of the `Event`s parsed from an `AsyncRead`
+///
+/// The stream ends once the reader reaches the end of its input, and all data read
+/// has been parsed. If the input ends within an element, or the parser's input buffer
+/// fills up without another event being parsed, an error is returned before.
+pub struct EventStream<R> {
+    reader: R,
+    parser: Parser,
+    buf: Vec<u8>,
+    // The number of bytes at the start of buf read but not fed to the parser yet
+    buffered: usize,
+    eof: bool,
+    done: bool
+}
+
+impl<R: AsyncRead + Unpin> EventStream<R> {
+    /// Returns a new `EventStream` reading from `reader`
+    pub fn new(reader: R) -> EventStream<R> {
+        EventStream::with_parser(reader, Parser::new())
+    }
+
+    /// Returns a new `EventStream` reading from `reader`, using a configured `parser`
+    pub fn with_parser(reader: R, parser: Parser) -> EventStream<R> {
+        EventStream {
+            reader,
+            parser,
+            buf: vec![0; 4096],
+            buffered: 0,
+            eof: false,
+            done: false
+        }
+    }
+
+    /// Returns a reference to the `Parser`
+    pub fn parser(&self) -> &Parser {
+        &self.parser
+    }
+
+    /// Returns a mutable reference to the `Parser`
+    pub fn parser_mut(&mut self) -> &mut Parser {
+        &mut self.parser
+    }
+
+    /// Returns the reader, dropping the parser and any data it has not parsed yet
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for EventStream<R> {
+    type Item = Result<Event, StreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if this.done {
+                return Poll::Ready(None);
+            }
+            if let Some(event) = (&mut this.parser).next() {
+                return Poll::Ready(Some(event.map_err(From::from)));
+            }
+            if this.parser.stopped() {
+                return Poll::Ready(None);
+            }
+            if this.buffered > 0 {
+                let fed = match this.parser.feed_bytes(&this.buf[..this.buffered]) {
+                    Ok(fed) => fed,
+                    Err(err) => return Poll::Ready(Some(Err(StreamError::Parser(err))))
+                };
+                this.buf.copy_within(fed..this.buffered, 0);
+                this.buffered -= fed;
+                if fed > 0 {
+                    continue;
+                }
+            }
+            if this.eof {
+                this.done = true;
+                return match this.parser.finish() {
+                    Ok(()) if this.buffered == 0 => Poll::Ready(None),
+                    Ok(()) => Poll::Ready(Some(Err(StreamError::BufferFull))),
+                    Err(err) => Poll::Ready(Some(Err(StreamError::Parser(err))))
+                };
+            }
+            let len = cmp::min(this.buf.len() - this.buffered, this.parser.free_capacity());
+            if len == 0 {
+                this.done = true;
+                return Poll::Ready(Some(Err(StreamError::BufferFull)));
+            }
+
+            let mut buf = ReadBuf::new(&mut this.buf[this.buffered..this.buffered + len]);
+            match Pin::new(&mut this.reader).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(StreamError::Io(err)))),
+                Poll::Pending => return Poll::Pending
+            }
+            let read = buf.filled().len();
+            if read == 0 {
+                this.eof = true;
+            } else {
+                this.buffered += read;
+            }
+        }
+    }
+}
+
+/// A `Stream` of the root `Element`s parsed from an `AsyncRead`
+pub struct ElementStream<R> {
+    events: EventStream<R>,
+    builder: ElementBuilder
+}
+
+impl<R: AsyncRead + Unpin> ElementStream<R> {
+    /// Returns a new `ElementStream` reading from `reader`
+    pub fn new(reader: R) -> ElementStream<R> {
+        ElementStream::with_parser(reader, Parser::new())
+    }
+
+    /// Returns a new `ElementStream` reading from `reader`, using a configured `parser`.
+    /// The `ElementBuilder` is configured to match the parser.
+    pub fn with_parser(reader: R, parser: Parser) -> ElementStream<R> {
+        let builder = parser.element_builder();
+        ElementStream {
+            events: EventStream::with_parser(reader, parser),
+            builder
+        }
+    }
+
+    /// Returns a mutable reference to the `ElementBuilder`, e.g. to define prefixes
+    pub fn builder_mut(&mut self) -> &mut ElementBuilder {
+        &mut self.builder
+    }
+
+    /// Returns the reader, dropping the parser and any data it has not parsed yet
+    pub fn into_inner(self) -> R {
+        self.events.into_inner()
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for ElementStream<R> {
+    type Item = Result<Element, StreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let event = match Pin::new(&mut this.events).poll_next(cx) {
+                Poll::Ready(Some(Ok(event))) => event,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending
+            };
+            if let Some(result) = this.builder.handle_event(Ok(event)) {
+                return Poll::Ready(Some(result.map_err(StreamError::Builder)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ElementStream, EventStream, StreamError};
+    use futures_core::Stream;
+    use parser::Parser;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+    use tokio::io::{duplex, AsyncWrite, DuplexStream};
+    use Event;
+
+    fn poll<S: Stream + Unpin>(stream: &mut S) -> Poll<Option<S::Item>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        Pin::new(stream).poll_next(&mut cx)
+    }
+
+    fn write(writer: &mut DuplexStream, data: &[u8]) {
+        let mut cx = Context::from_waker(Waker::noop());
+        let written = Pin::new(writer).poll_write(&mut cx, data);
+        assert!(matches!(written, Poll::Ready(Ok(n)) if n == data.len()));
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let (mut writer, reader) = duplex(64);
+        let mut stream = EventStream::new(reader);
+        assert!(poll(&mut stream).is_pending());
+
+        write(&mut writer, b"<a>x\xc3");
+        assert!(match poll(&mut stream) {
+            Poll::Ready(Some(Ok(Event::ElementStart(tag)))) => tag.name == "a",
+            _ => false
+        });
+        assert!(poll(&mut stream).is_pending());
+
+        write(&mut writer, b"\xa9</a>");
+        assert!(match poll(&mut stream) {
+            Poll::Ready(Some(Ok(Event::Characters(text)))) => text == "x\u{e9}",
+            _ => false
+        });
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Ok(Event::ElementEnd(_))))));
+        assert!(poll(&mut stream).is_pending());
+
+        drop(writer);
+        assert!(matches!(poll(&mut stream), Poll::Ready(None)));
+    }
+
+    #[test]
+    fn test_event_stream_truncated() {
+        let (mut writer, reader) = duplex(64);
+        let mut stream = EventStream::new(reader);
+        write(&mut writer, b"<a><b");
+        drop(writer);
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Ok(Event::ElementStart(_))))));
+        assert!(match poll(&mut stream) {
+            Poll::Ready(Some(Err(StreamError::Parser(err)))) => {
+                err.msg == "Unexpected end of input"
+            }
+            _ => false
+        });
+        assert!(matches!(poll(&mut stream), Poll::Ready(None)));
+
+        // The parser cannot accept any input
+        let (mut writer, reader) = duplex(64);
+        let mut parser = Parser::new();
+        parser.set_input_capacity(Some(0));
+        let mut stream = EventStream::with_parser(reader, parser);
+        write(&mut writer, b"<a/>");
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Err(StreamError::BufferFull)))));
+        assert!(matches!(poll(&mut stream), Poll::Ready(None)));
+
+        // Data the parser did not accept is fed again
+        let (mut writer, reader) = duplex(64);
+        let mut parser = Parser::new();
+        parser.set_input_capacity(Some(2));
+        let mut stream = EventStream::with_parser(reader, parser);
+        write(&mut writer, "<a>\u{20ac}\u{20ac}\u{20ac}</a>".as_bytes());
+        drop(writer);
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Ok(Event::ElementStart(_))))));
+        assert!(match poll(&mut stream) {
+            Poll::Ready(Some(Ok(Event::Characters(text)))) => text == "\u{20ac}\u{20ac}\u{20ac}",
+            _ => false
+        });
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Ok(Event::ElementEnd(_))))));
+        assert!(matches!(poll(&mut stream), Poll::Ready(None)));
+
+        // Data following the root element is left alone
+        let (mut writer, reader) = duplex(64);
+        let mut parser = Parser::new();
+        parser.set_input_capacity(Some(8));
+        parser.set_stop_at_root_end(true);
+        let mut stream = EventStream::with_parser(reader, parser);
+        write(&mut writer, b"<a/>\x16\x03\x01\x02\x00\x01\x00\x01\xfc");
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Ok(Event::ElementStart(_))))));
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Ok(Event::ElementEnd(_))))));
+        assert!(matches!(poll(&mut stream), Poll::Ready(None)));
+    }
+
+    #[test]
+    fn test_element_stream() {
+        let (mut writer, reader) = duplex(64);
+        let mut parser = Parser::new();
+        parser.set_multiple_documents(true);
+        parser.set_input_capacity(Some(8));
+        let mut stream = ElementStream::with_parser(reader, parser);
+
+        write(&mut writer, b"<log n='1'/>\n<log n='2'>");
+        let elem = match poll(&mut stream) {
+            Poll::Ready(Some(Ok(elem))) => elem,
+            _ => panic!("Expected an element")
+        };
+        assert_eq!(elem.get_attribute("n", None), Some("1"));
+        assert!(poll(&mut stream).is_pending());
+
+        write(&mut writer, b"</log><1/>");
+        let elem = match poll(&mut stream) {
+            Poll::Ready(Some(Ok(elem))) => elem,
+            _ => panic!("Expected an element")
+        };
+        assert_eq!(elem.get_attribute("n", None), Some("2"));
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Err(StreamError::Parser(_))))));
+    }
+}
@@ -0,0 +1,797 @@
+// RustyXML
+// Copyright (c) 2013-2015 Florian Zeitz
+//
+// This project is MIT licensed.
+// Please see the COPYING file for more information.
+
+use super::{needs_char_ref, Event, StartTag, EndTag, XmlVersion};
+use parser::is_name;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+const XMLNS_NS: &str = "http://www.w3.org/2000/xmlns/";
+
+// Writes text escaped like `escape()` does, without allocating
+pub(crate) fn write_escaped<W: Write + ?Sized>(writer: &mut W, text: &str) -> io::Result<()> {
+    write_escaped_as(writer, text, XmlVersion::Version10)
+}
+
+// Writes text escaped for a document of the given version,
+// XML 1.1 documents also need character references for control characters.
+// Characters the version does not allow are reported as an error.
+pub(crate) fn write_escaped_as<W: Write + ?Sized>(writer: &mut W, text: &str,
+                                                  version: XmlVersion) -> io::Result<()> {
+    let xml11 = version == XmlVersion::Version11;
+    if !(is_valid_text(text) || (xml11 && !text.contains(['\u{0}', '\u{FFFE}', '\u{FFFF}']))) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Character not allowed in XML"));
+    }
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        let entity = match c {
+            '&' => "&amp;",
+            '<' => "&lt;",
+            '>' => "&gt;",
+            '\'' => "&apos;",
+            '"' => "&quot;",
+            c if xml11 && needs_char_ref(c) => "",
+            _ => continue
+        };
+        writer.write_all(&text.as_bytes()[start..i])?;
+        if entity.is_empty() {
+            write!(writer, "&#x{:X};", c as u32)?;
+        } else {
+            writer.write_all(entity.as_bytes())?;
+        }
+        start = i + c.len_utf8();
+    }
+    writer.write_all(&text.as_bytes()[start..])
+}
+
+// Whether a local name or prefix is a Name without colons
+fn is_ncname(name: &str) -> bool {
+    is_name(name) && !name.contains(':')
+}
+
+// Whether text only contains characters XML 1.0 allows
+fn is_valid_text(text: &str) -> bool {
+    !text.contains(|c| matches!(c, '\u{0}'..='\u{8}' | '\u{B}' | '\u{C}' | '\u{E}'..='\u{1F}'
+                                 | '\u{FFFE}' | '\u{FFFF}'))
+}
+
+// Counts the bytes written to another writer
+pub(crate) struct CountingWriter<'a, W: 'a + ?Sized> {
+    inner: &'a mut W,
+    pub(crate) count: usize
+}
+
+impl<'a, W: Write + ?Sized> CountingWriter<'a, W> {
+    pub(crate) fn new(inner: &'a mut W) -> CountingWriter<'a, W> {
+        CountingWriter { inner, count: 0 }
+    }
+}
+
+impl<'a, W: Write + ?Sized> Write for CountingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug)]
+/// The structure returned for errors encountered by an `XmlWriter`
+pub enum WriterError {
+    /// Errors encountered while writing the output
+    Io(io::Error),
+    /// An end tag was written without an open element
+    NoOpenElement,
+    /// An end tag did not match the open element
+    MismatchedEnd,
+    /// An attribute was written after the content of an element
+    AttributeAfterContent,
+    /// An attribute was written twice for the same element
+    DuplicateAttribute,
+    /// A namespace prefix was bound twice for the same element,
+    /// or rebound after a name in its start tag used it
+    NamespaceConflict,
+    /// Content other than comments, processing instructions and whitespace
+    /// was written outside of the root element
+    ContentOutsideRoot,
+    /// Content that cannot be represented, e.g. a comment containing `--`,
+    /// an invalid name or a character XML 1.0 does not allow
+    InvalidContent,
+    /// The output was finished while elements were still open
+    UnclosedElements
+}
+
+impl Error for WriterError {
+    fn description(&self) -> &str {
+        match *self {
+            WriterError::Io(_) => "Failed to write output",
+            WriterError::NoOpenElement => "No open element to end",
+            WriterError::MismatchedEnd => "End tag does not match open element",
+            WriterError::AttributeAfterContent => "Attribute written after content",
+            WriterError::DuplicateAttribute => "Duplicate attribute",
+            WriterError::NamespaceConflict => "Namespace prefix bound twice",
+            WriterError::ContentOutsideRoot => "Content outside of root element",
+            WriterError::InvalidContent => "Content cannot be represented",
+            WriterError::UnclosedElements => "Elements still open"
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            WriterError::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for WriterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriterError::Io(ref err) => err.fmt(f),
+            WriterError::NoOpenElement => write!(f, "No open element to end"),
+            WriterError::MismatchedEnd => write!(f, "End tag does not match open element"),
+            WriterError::AttributeAfterContent => write!(f, "Attribute written after content"),
+            WriterError::DuplicateAttribute => write!(f, "Duplicate attribute"),
+            WriterError::NamespaceConflict => write!(f, "Namespace prefix bound twice"),
+            WriterError::ContentOutsideRoot => write!(f, "Content outside of root element"),
+            WriterError::InvalidContent => write!(f, "Content cannot be represented"),
+            WriterError::UnclosedElements => write!(f, "Elements still open")
+        }
+    }
+}
+
+impl From<io::Error> for WriterError {
+    fn from(err: io::Error) -> WriterError { WriterError::Io(err) }
+}
+
+// An open element
+struct Open {
+    name: String,
+    ns: Option<String>,
+    qname: String
+}
+
+/// An XML Writer, writing well-formed XML incrementally to an `io::Write`
+///
+/// Output is written as it is produced, wrap the writer in an `io::BufWriter` to
+/// reduce the number of writes. Namespace prefixes are declared as needed,
+/// using the prefixes set by `define_prefix()` where possible.
+///
+/// ~~~
+/// use xml::XmlWriter;
+///
+/// let mut writer = XmlWriter::new(Vec::new());
+/// writer.start_element("feed", Some("http://www.w3.org/2005/Atom")).unwrap();
+/// writer.start_element("title", Some("http://www.w3.org/2005/Atom")).unwrap();
+/// writer.attribute("type", None, "text").unwrap();
+/// writer.text("Fish & Chips").unwrap();
+/// writer.end_element().unwrap();
+/// writer.end_element().unwrap();
+/// let output = writer.finish().unwrap();
+/// assert_eq!(String::from_utf8(output).unwrap(),
+///            "<feed xmlns='http://www.w3.org/2005/Atom'>\
+///             <title type='text'>Fish &amp; Chips</title></feed>");
+/// ~~~
+pub struct XmlWriter<W> {
+    writer: W,
+    // The preferred prefixes for namespaces
+    prefixes: HashMap<String, String>,
+    // The namespace declarations of each open element, the first scope holds the
+    // predefined ones
+    scopes: Vec<Vec<(Option<String>, String)>>,
+    open: Vec<Open>,
+    // Namespace declarations reported by `StartPrefixMapping` for the next element
+    mappings: Vec<(Option<String>, String)>,
+    // Namespace declarations not written yet
+    declarations: Vec<(Option<String>, String)>,
+    // The attributes written for the current start tag
+    attributes: Vec<(String, Option<String>)>,
+    // The prefixes used by the names in the current start tag,
+    // `None` standing for the default namespace
+    used: Vec<Option<String>>,
+    in_start_tag: bool,
+    root_closed: bool,
+    // Whether anything was written for the current document
+    written: bool
+}
+
+impl<W: Write> XmlWriter<W> {
+    /// Returns a new `XmlWriter` writing to `writer`
+    pub fn new(writer: W) -> XmlWriter<W> {
+        XmlWriter {
+            writer,
+            prefixes: HashMap::new(),
+            scopes: vec![vec![(Some("xml".to_owned()), XML_NS.to_owned()), (None, String::new())]],
+            open: Vec::new(),
+            mappings: Vec::new(),
+            declarations: Vec::new(),
+            attributes: Vec::new(),
+            used: Vec::new(),
+            in_start_tag: false,
+            root_closed: false,
+            written: false
+        }
+    }
+
+    /// Sets the prefix to declare for a namespace when it is first needed
+    pub fn define_prefix(&mut self, prefix: &str, ns: &str) {
+        self.prefixes.insert(ns.to_owned(), prefix.to_owned());
+    }
+
+    /// Returns a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Returns a mutable reference to the underlying writer.
+    /// Writing to it directly may break the well-formedness of the output.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Returns the number of elements currently open
+    pub fn depth(&self) -> usize {
+        self.open.len()
+    }
+
+    /// Flushes the underlying writer
+    pub fn flush(&mut self) -> Result<(), WriterError> {
+        Ok(self.writer.flush()?)
+    }
+
+    /// Returns the underlying writer, after checking that all elements were closed
+    /// and flushing it
+    pub fn finish(mut self) -> Result<W, WriterError> {
+        if !self.open.is_empty() {
+            return Err(WriterError::UnclosedElements);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Returns the underlying writer, without checking the output is complete
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    // The namespace bound to a prefix, with an empty string for no namespace
+    fn lookup(&self, prefix: Option<&str>) -> Option<&str> {
+        self.scopes.iter().rev().flat_map(|x| x.iter().rev())
+                   .find(|x| x.0.as_deref() == prefix)
+                   .map(|x| &x.1[..])
+    }
+
+    fn declared_here(&self, prefix: Option<&str>) -> bool {
+        self.scopes.last().is_some_and(|x| x.iter().any(|x| x.0.as_deref() == prefix))
+    }
+
+    fn in_use(&self, prefix: Option<&str>) -> bool {
+        self.used.iter().any(|x| x.as_deref() == prefix)
+    }
+
+    // Binds a prefix for the current start tag, queueing the declaration if needed.
+    // Rebinding a prefix the start tag already used would change the namespace of its names.
+    fn declare(&mut self, prefix: Option<&str>, uri: &str) -> Result<(), WriterError> {
+        if prefix.is_some_and(|x| !is_ncname(x)) || !is_valid_text(uri) {
+            return Err(WriterError::InvalidContent);
+        }
+        if let Some(bound) = self.scopes.last().unwrap().iter().find(|x| x.0.as_deref() == prefix) {
+            return if bound.1 == uri { Ok(()) } else { Err(WriterError::NamespaceConflict) };
+        }
+        let bound = self.lookup(prefix) == Some(uri);
+        if !bound && (prefix == Some("xml") || prefix == Some("xmlns") || uri == XMLNS_NS
+                      || uri == XML_NS || (prefix.is_some() && uri.is_empty())
+                      || self.in_use(prefix)) {
+            return Err(WriterError::NamespaceConflict);
+        }
+        let decl = (prefix.map(|x| x.to_owned()), uri.to_owned());
+        if !bound {
+            self.declarations.push(decl.clone());
+        }
+        self.scopes.last_mut().unwrap().push(decl);
+        Ok(())
+    }
+
+    fn write_declarations(&mut self) -> Result<(), WriterError> {
+        for (prefix, uri) in self.declarations.drain(..) {
+            match prefix {
+                Some(prefix) => write!(self.writer, " xmlns:{}='", prefix)?,
+                None => self.writer.write_all(b" xmlns='")?
+            }
+            write_escaped(&mut self.writer, &uri)?;
+            self.writer.write_all(b"'")?;
+        }
+        Ok(())
+    }
+
+    // Finds or declares a non-empty prefix for a namespace
+    fn prefix_for(&mut self, uri: &str, hint: Option<&str>) -> Result<String, WriterError> {
+        if let Some(hint) = hint {
+            if self.lookup(Some(hint)) == Some(uri)
+                || !(self.declared_here(Some(hint)) || self.in_use(Some(hint))) {
+                self.declare(Some(hint), uri)?;
+                return Ok(hint.to_owned());
+            }
+        }
+        let bound = self.scopes.iter().rev().flat_map(|x| x.iter().rev())
+                        .filter(|x| x.1 == uri)
+                        .filter_map(|x| x.0.clone())
+                        .find(|x| self.lookup(Some(x)) == Some(uri));
+        if let Some(prefix) = bound {
+            return Ok(prefix);
+        }
+        let prefix = match self.prefixes.get(uri) {
+            Some(prefix) if !(self.declared_here(Some(prefix)) || self.in_use(Some(prefix))) => {
+                prefix.clone()
+            }
+            _ => (0..).map(|x| format!("ns{}", x))
+                      .find(|x| self.lookup(Some(x)).is_none())
+                      .unwrap()
+        };
+        self.declare(Some(&prefix), uri)?;
+        Ok(prefix)
+    }
+
+    fn close_start_tag(&mut self) -> Result<(), WriterError> {
+        if self.in_start_tag {
+            self.writer.write_all(b">")?;
+            self.in_start_tag = false;
+            self.attributes.clear();
+            self.used.clear();
+        }
+        Ok(())
+    }
+
+    // Checks content other than whitespace, comments and PIs may be written here
+    fn check_content(&mut self) -> Result<(), WriterError> {
+        if self.open.is_empty() {
+            return Err(WriterError::ContentOutsideRoot);
+        }
+        self.close_start_tag()
+    }
+
+    fn start(&mut self, name: &str, ns: Option<&str>, hint: Option<&str>,
+             decls: Vec<(Option<String>, String)>) -> Result<(), WriterError> {
+        if self.open.is_empty() && self.root_closed {
+            return Err(WriterError::ContentOutsideRoot);
+        }
+        if !is_ncname(name) || hint.is_some_and(|x| !is_ncname(x)) {
+            return Err(WriterError::InvalidContent);
+        }
+        self.close_start_tag()?;
+        self.scopes.push(Vec::new());
+        let result = self.start_scoped(name, ns, hint, decls);
+        if result.is_err() {
+            self.scopes.pop();
+            self.declarations.clear();
+        }
+        result
+    }
+
+    fn start_scoped(&mut self, name: &str, ns: Option<&str>, hint: Option<&str>,
+                    decls: Vec<(Option<String>, String)>) -> Result<(), WriterError> {
+        for (prefix, uri) in decls {
+            self.declare(prefix.as_deref(), &uri)?;
+        }
+
+        let uri = ns.unwrap_or("");
+        let qname = if hint.is_none() && self.lookup(None) == Some(uri) {
+            name.to_owned()
+        } else if ns.is_none() || (hint.is_none() && !self.declared_here(None)
+                                   && !self.prefixes.contains_key(uri)
+                                   && self.scopes.iter().flatten()
+                                          .all(|x| x.1 != uri || x.0.is_none())) {
+            // Declare the namespace as the default namespace
+            self.declare(None, uri)?;
+            name.to_owned()
+        } else {
+            format!("{}:{}", self.prefix_for(uri, hint)?, name)
+        };
+
+        write!(self.writer, "<{}", qname)?;
+        self.write_declarations()?;
+        self.used.push(qname.split_once(':').map(|x| x.0.to_owned()));
+        self.written = true;
+        self.open.push(Open { name: name.to_owned(), ns: ns.map(|x| x.to_owned()), qname });
+        self.in_start_tag = true;
+        Ok(())
+    }
+
+    /// Starts an element.
+    ///
+    /// Elements without a namespace are written unprefixed, the default namespace
+    /// is reset if needed. Elements in a namespace use a prefix bound to it,
+    /// or declare it, as the default namespace unless a prefix was set by
+    /// `define_prefix()`.
+    pub fn start_element(&mut self, name: &str, ns: Option<&str>) -> Result<(), WriterError> {
+        let mappings = std::mem::take(&mut self.mappings);
+        self.start(name, ns, None, mappings)
+    }
+
+    /// Writes an attribute of the element just started.
+    ///
+    /// `xmlns` attributes and those in the `http://www.w3.org/2000/xmlns/` namespace
+    /// declare namespace prefixes for the element and its content.
+    pub fn attribute(&mut self, name: &str, ns: Option<&str>, value: &str)
+                     -> Result<(), WriterError> {
+        self.attribute_with_prefix(name, ns, None, value)
+    }
+
+    fn attribute_with_prefix(&mut self, name: &str, ns: Option<&str>, hint: Option<&str>,
+                             value: &str) -> Result<(), WriterError> {
+        if !self.in_start_tag {
+            return Err(WriterError::AttributeAfterContent);
+        }
+        if !is_ncname(name) || !is_valid_text(value) || ns == Some("") {
+            return Err(WriterError::InvalidContent);
+        }
+        match ns {
+            None if name == "xmlns" => {
+                self.declare(None, value)?;
+                return self.write_declarations();
+            }
+            Some(XMLNS_NS) => {
+                self.declare(Some(name), value)?;
+                return self.write_declarations();
+            }
+            _ => ()
+        }
+
+        if self.attributes.iter().any(|x| x.0 == name && x.1.as_deref() == ns) {
+            return Err(WriterError::DuplicateAttribute);
+        }
+        self.attributes.push((name.to_owned(), ns.map(|x| x.to_owned())));
+        match ns {
+            Some(ns) => {
+                let prefix = self.prefix_for(ns, hint)?;
+                self.write_declarations()?;
+                write!(self.writer, " {}:{}='", prefix, name)?;
+                self.used.push(Some(prefix));
+            }
+            None => write!(self.writer, " {}='", name)?
+        }
+        write_escaped(&mut self.writer, value)?;
+        self.writer.write_all(b"'")?;
+        Ok(())
+    }
+
+    /// Ends the innermost open element
+    pub fn end_element(&mut self) -> Result<(), WriterError> {
+        let elem = self.open.pop().ok_or(WriterError::NoOpenElement)?;
+        if self.in_start_tag {
+            self.writer.write_all(b"/>")?;
+            self.in_start_tag = false;
+            self.attributes.clear();
+            self.used.clear();
+        } else {
+            write!(self.writer, "</{}>", elem.qname)?;
+        }
+        self.scopes.pop();
+        self.root_closed = self.open.is_empty();
+        Ok(())
+    }
+
+    /// Writes character data, which may only be whitespace outside of the root element
+    pub fn text(&mut self, text: &str) -> Result<(), WriterError> {
+        if self.open.is_empty() && text.chars().all(|c| matches!(c, ' ' | '\t' | '\r' | '\n')) {
+            self.writer.write_all(text.as_bytes())?;
+            self.written |= !text.is_empty();
+            return Ok(());
+        }
+        if !is_valid_text(text) {
+            return Err(WriterError::InvalidContent);
+        }
+        self.check_content()?;
+        Ok(write_escaped(&mut self.writer, text)?)
+    }
+
+    /// Writes a CDATA section, split into several if `text` contains `]]>`
+    pub fn cdata(&mut self, text: &str) -> Result<(), WriterError> {
+        if !is_valid_text(text) {
+            return Err(WriterError::InvalidContent);
+        }
+        self.check_content()?;
+        let text = text.replace("]]>", "]]]]><![CDATA[>");
+        Ok(write!(self.writer, "<![CDATA[{}]]>", text)?)
+    }
+
+    /// Writes a comment
+    pub fn comment(&mut self, text: &str) -> Result<(), WriterError> {
+        if text.contains("--") || text.ends_with('-') || !is_valid_text(text) {
+            return Err(WriterError::InvalidContent);
+        }
+        self.close_start_tag()?;
+        self.written = true;
+        Ok(write!(self.writer, "<!--{}-->", text)?)
+    }
+
+    /// Writes a processing instruction, `text` being its target followed by its data.
+    /// An XML declaration, with the target `xml`, has to be written first.
+    pub fn pi(&mut self, text: &str) -> Result<(), WriterError> {
+        let target = text.split([' ', '\t', '\r', '\n']).next().unwrap();
+        if !is_name(target) || (self.written && target.eq_ignore_ascii_case("xml"))
+           || text.contains("?>") || !is_valid_text(text) {
+            return Err(WriterError::InvalidContent);
+        }
+        self.close_start_tag()?;
+        self.written = true;
+        Ok(write!(self.writer, "<?{}?>", text)?)
+    }
+
+    /// Writes an entity reference
+    pub fn entity_ref(&mut self, name: &str) -> Result<(), WriterError> {
+        if !is_name(name) {
+            return Err(WriterError::InvalidContent);
+        }
+        self.check_content()?;
+        Ok(write!(self.writer, "&{};", name)?)
+    }
+
+    /// Writes an `Event`, as returned by a `Parser` or another `EventSource`.
+    ///
+    /// The prefixes of start tags are kept where possible, `ElementEnd` has to match
+    /// the open element. `StartDocument` allows another root element to follow.
+    pub fn write_event(&mut self, event: &Event) -> Result<(), WriterError> {
+        match *event {
+            Event::ElementStart(StartTag { ref name, ref ns, ref prefix, ref attributes }) => {
+                let mut decls = std::mem::take(&mut self.mappings);
+                for ((name, ns), value) in attributes {
+                    match ns.as_deref() {
+                        None if name == "xmlns" => decls.push((None, value.clone())),
+                        Some(XMLNS_NS) => decls.push((Some(name.clone()), value.clone())),
+                        _ => ()
+                    }
+                }
+                self.start(name, ns.as_deref(), prefix.as_deref(), decls)?;
+                for ((name, ns), value) in attributes {
+                    self.attribute(name, ns.as_deref(), value)?;
+                }
+                Ok(())
+            }
+            Event::ElementEnd(EndTag { ref name, ref ns, .. }) => {
+                match self.open.last() {
+                    Some(elem) if elem.name == *name && elem.ns == *ns => self.end_element(),
+                    Some(_) => Err(WriterError::MismatchedEnd),
+                    None => Err(WriterError::NoOpenElement)
+                }
+            }
+            Event::Characters(ref text) | Event::Whitespace(ref text) => self.text(text),
+            Event::CDATA(ref text) => self.cdata(text),
+            Event::Comment(ref text) => self.comment(text),
+            Event::PI(ref text) => self.pi(text),
+            Event::EntityRef(ref name) => self.entity_ref(name),
+            Event::StartPrefixMapping { ref prefix, ref uri } => {
+                self.mappings.push((prefix.clone(), uri.clone()));
+                Ok(())
+            }
+            Event::EndPrefixMapping { .. } | Event::EndDocument => Ok(()),
+            Event::StartDocument => {
+                if !self.open.is_empty() {
+                    return Err(WriterError::UnclosedElements);
+                }
+                self.root_closed = false;
+                self.written = false;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WriterError, XmlWriter};
+    use element_builder::ElementBuilder;
+    use parser::Parser;
+    use std::io::{self, Write};
+    use {Element, Event};
+
+    fn output(writer: XmlWriter<Vec<u8>>) -> String {
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_write_elements() {
+        let mut w = XmlWriter::new(Vec::new());
+        w.pi("xml version='1.0'").unwrap();
+        w.start_element("a", None).unwrap();
+        w.attribute("x", None, "'<&>\"").unwrap();
+        w.text("1 < 2 & 3").unwrap();
+        w.start_element("b", None).unwrap();
+        w.end_element().unwrap();
+        w.cdata("x]]>y").unwrap();
+        w.comment(" c ").unwrap();
+        w.entity_ref("nbsp").unwrap();
+        w.end_element().unwrap();
+        w.text("\n").unwrap();
+        assert_eq!(output(w), "<?xml version='1.0'?><a x='&apos;&lt;&amp;&gt;&quot;'>\
+                               1 &lt; 2 &amp; 3<b/><![CDATA[x]]]]><![CDATA[>y]]>\
+                               <!-- c -->&nbsp;</a>\n");
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let mut w = XmlWriter::new(Vec::new());
+        w.define_prefix("b", "urn:b");
+        w.start_element("a", Some("urn:a")).unwrap();
+        w.start_element("b", Some("urn:b")).unwrap();
+        w.attribute("x", Some("urn:b"), "1").unwrap();
+        w.attribute("y", Some("urn:c"), "2").unwrap();
+        w.attribute("lang", Some("http://www.w3.org/XML/1998/namespace"), "en").unwrap();
+        w.start_element("c", None).unwrap();
+        w.start_element("d", Some("urn:a")).unwrap();
+        w.end_element().unwrap();
+        w.end_element().unwrap();
+        w.end_element().unwrap();
+        w.start_element("b", Some("urn:b")).unwrap();
+        w.end_element().unwrap();
+        w.end_element().unwrap();
+        assert_eq!(output(w), "<a xmlns='urn:a'><b:b xmlns:b='urn:b' b:x='1' \
+                               xmlns:ns0='urn:c' ns0:y='2' xml:lang='en'>\
+                               <c xmlns=''><d xmlns='urn:a'/></c></b:b>\
+                               <b:b xmlns:b='urn:b'/></a>");
+    }
+
+    #[test]
+    fn test_namespace_conflicts() {
+        let mut w = XmlWriter::new(Vec::new());
+        w.start_element("a", Some("urn:a")).unwrap();
+        w.attribute("xmlns", None, "urn:a").unwrap();
+        assert!(matches!(w.attribute("xmlns", None, ""), Err(WriterError::NamespaceConflict)));
+        w.start_element("b", Some("urn:a")).unwrap();
+        assert!(matches!(w.attribute("xmlns", None, ""), Err(WriterError::NamespaceConflict)));
+        w.end_element().unwrap();
+        w.end_element().unwrap();
+        assert_eq!(output(w), "<a xmlns='urn:a'><b/></a>");
+
+        let mut w = XmlWriter::new(Vec::new());
+        w.define_prefix("p", "urn:p1");
+        w.start_element("a", Some("urn:p1")).unwrap();
+        w.start_element("b", Some("urn:p1")).unwrap();
+        let xmlns = Some("http://www.w3.org/2000/xmlns/");
+        assert!(matches!(w.attribute("p", xmlns, "urn:p2"), Err(WriterError::NamespaceConflict)));
+        w.attribute("x", Some("urn:p1"), "1").unwrap();
+        assert!(matches!(w.attribute("q", xmlns, ""), Err(WriterError::NamespaceConflict)));
+        assert!(matches!(w.attribute("q", xmlns, "http://www.w3.org/XML/1998/namespace"),
+                         Err(WriterError::NamespaceConflict)));
+        assert!(matches!(w.attribute("xmlns", None, "http://www.w3.org/XML/1998/namespace"),
+                         Err(WriterError::NamespaceConflict)));
+        w.start_element("c", None).unwrap();
+        w.attribute("y", Some("urn:p1"), "2").unwrap();
+        assert!(matches!(w.attribute("p", xmlns, "urn:p2"), Err(WriterError::NamespaceConflict)));
+        w.end_element().unwrap();
+        w.end_element().unwrap();
+        w.end_element().unwrap();
+        assert_eq!(output(w), "<p:a xmlns:p='urn:p1'><p:b p:x='1'><c p:y='2'/></p:b></p:a>");
+
+        let mut w = XmlWriter::new(Vec::new());
+        w.define_prefix("p", "urn:p1");
+        w.define_prefix("p", "urn:p2");
+        w.start_element("a", Some("urn:p1")).unwrap();
+        w.start_element("b", Some("urn:p1")).unwrap();
+        w.attribute("x", Some("urn:p2"), "1").unwrap();
+        w.end_element().unwrap();
+        w.end_element().unwrap();
+        assert_eq!(output(w), "<p:a xmlns:p='urn:p1'><p:b xmlns:ns0='urn:p2' ns0:x='1'/></p:a>");
+    }
+
+    #[test]
+    fn test_write_events() {
+        let inputs = [
+            "<a xmlns='urn:a' xmlns:b='urn:b'><b:c x='1' b:y='&amp;'>t<![CDATA[<d>]]>\
+             <!--c--><?pi x?></b:c><e xmlns=''/></a>",
+            "<x:a xmlns:x='urn:x'><b><x:c/></b>text</x:a>",
+            "<a><p:b xmlns:p='urn:p'/><p:b xmlns:p='urn:q'/></a>"
+        ];
+        for input in &inputs {
+            let mut p = Parser::new();
+            p.feed_str(input);
+            let mut w = XmlWriter::new(Vec::new());
+            for event in &mut p {
+                w.write_event(&event.unwrap()).unwrap();
+            }
+            assert_eq!(output(w), *input);
+        }
+
+        let elem: Element = inputs[0].parse().unwrap();
+        let mut w = XmlWriter::new(Vec::new());
+        for event in elem.events() {
+            w.write_event(&event).unwrap();
+        }
+        let mut p = Parser::new();
+        p.feed_str(&output(w));
+        let mut builder = ElementBuilder::new();
+        assert_eq!(builder.build_from(&mut p), Some(Ok(elem)));
+    }
+
+    #[test]
+    fn test_prefix_mappings() {
+        let mut p = Parser::new();
+        p.set_report_prefix_mappings(true);
+        p.feed_str("<p:a xmlns:p='urn:p'><p:b/></p:a>");
+        let mut w = XmlWriter::new(Vec::new());
+        for event in &mut p {
+            w.write_event(&event.unwrap()).unwrap();
+        }
+        assert_eq!(output(w), "<p:a xmlns:p='urn:p'><p:b/></p:a>");
+    }
+
+    #[test]
+    fn test_misuse() {
+        let mut w = XmlWriter::new(Vec::new());
+        assert!(matches!(w.end_element(), Err(WriterError::NoOpenElement)));
+        assert!(matches!(w.text("x"), Err(WriterError::ContentOutsideRoot)));
+        w.start_element("a", None).unwrap();
+        w.attribute("x", None, "1").unwrap();
+        assert!(matches!(w.attribute("x", None, "2"), Err(WriterError::DuplicateAttribute)));
+        assert!(matches!(w.attribute("xmlns", None, "urn:a"), Err(WriterError::NamespaceConflict)));
+        w.text("x").unwrap();
+        assert!(matches!(w.attribute("y", None, "1"), Err(WriterError::AttributeAfterContent)));
+        assert!(matches!(w.comment("a--b"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.pi("a?>"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.text("\u{1}"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.cdata("\u{FFFF}"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.start_element("a b<", None), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.start_element("p:b", None), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.pi(" x"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.pi("1x"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.pi("xml version='1.0'"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.pi("XmL"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.entity_ref("a\"b"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.entity_ref(""), Err(WriterError::InvalidContent)));
+        w.start_element("b", None).unwrap();
+        assert_eq!(w.depth(), 2);
+        let mut p = Parser::new();
+        p.feed_str("<c></c>");
+        let end = p.last().unwrap().unwrap();
+        assert!(matches!(w.write_event(&end), Err(WriterError::MismatchedEnd)));
+        w.end_element().unwrap();
+        w.end_element().unwrap();
+        assert!(matches!(w.start_element("a", None), Err(WriterError::ContentOutsideRoot)));
+
+        let mut w = XmlWriter::new(Vec::new());
+        w.start_element("a", None).unwrap();
+        assert!(matches!(w.attribute("1", None, "x"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.attribute("x", None, "\u{0}"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.attribute("xmlns", None, "\u{1}"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.attribute("x", Some(""), "1"), Err(WriterError::InvalidContent)));
+        assert!(matches!(w.finish(), Err(WriterError::UnclosedElements)));
+
+        let mut w = XmlWriter::new(Vec::new());
+        w.text("\n").unwrap();
+        assert!(matches!(w.pi("xml version='1.0'"), Err(WriterError::InvalidContent)));
+        w.pi("xml-stylesheet href='a.xsl'").unwrap();
+        w.start_element("a", None).unwrap();
+        w.end_element().unwrap();
+        w.write_event(&Event::StartDocument).unwrap();
+        w.pi("xml version='1.0'").unwrap();
+        assert_eq!(output(w), "\n<?xml-stylesheet href='a.xsl'?><a/><?xml version='1.0'?>");
+    }
+
+    struct Failing;
+
+    impl Write for Failing {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_io_error() {
+        let mut w = XmlWriter::new(Failing);
+        assert!(matches!(w.start_element("a", None), Err(WriterError::Io(_))));
+    }
+}